@@ -10,16 +10,27 @@ extern crate simple_error;
 extern crate bitflags;
 
 use libc::{atexit, ioctl, winsize, TIOCGWINSZ};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
 use nix::Error;
-use std::io::{BufRead, ErrorKind, Read, Write};
+use regex::{Regex, RegexBuilder};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::process::exit;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
 use std::vec::Vec;
 use termios::{
     tcsetattr, Termios, BRKINT, CS8, ECHO, ICANON, ICRNL, IEXTEN, INPCK, ISIG, ISTRIP, IXON, OPOST,
     TCSAFLUSH, VMIN, VTIME,
 };
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 /*** defines ***/
 fn unwrap_or_die<T, E>(msg: &str, r: std::result::Result<T, E>) -> T
@@ -29,15 +40,18 @@ where
     r.map_err(|e| die(&format!("{}: {:#?}", msg, e))).unwrap()
 }
 
-type Result<T> = std::result::Result<T, Box<std::error::Error>>;
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-fn ctrl_key(k: u8) -> u8 {
-    k & 0x1f
+fn ctrl_key(k: char) -> char {
+    ((k as u8) & 0x1f) as char
 }
 
 type PromptCallback = fn(&mut EditorConfig, &str, &EditorKey);
 
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+// A part of the screen: top, left, height, width
+type Rect = (usize, usize, usize, usize);
+
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy)]
 enum EditorKey {
     ArrowLeft,
     ArrowRight,
@@ -53,19 +67,34 @@ enum EditorKey {
     Return,
     Escape,
 
-    Char(u8),
+    Char(char),
+    Alt(char),
 }
 
 const BYOTE_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 const BYOTE_TAB_STOP: usize = 8;
 const BYOTE_QUIT_TIMES: u8 = 3;
-
-const BACKSPACE: u8 = 127;
+const BYOTE_STATUS_TIMEOUT_SECS: u64 = 5;
+const BYOTE_BACKUP_FILES: bool = false;
+const BYOTE_SWAP_INTERVAL_SECS: u64 = 2;
+const BYOTE_KILL_RING_SIZE: usize = 16;
+const BYOTE_HISTORY_SIZE: usize = 100;
+// Copy to and paste from the terminal's clipboard with OSC 52 escape sequences
+const BYOTE_OSC52: bool = true;
+// Shell commands that put stdin on, and print, the system clipboard, e.g. "xclip -i -sel c"
+const BYOTE_CLIPBOARD_COPY_COMMAND: Option<&str> = None;
+const BYOTE_CLIPBOARD_PASTE_COMMAND: Option<&str> = None;
+
+const BACKSPACE: char = '\x7f';
+
+// Bytes of a file that aren't valid UTF-8 are stored in `ERow::chars` as characters from the
+// end of the last private use plane: byte `b` becomes `RAW_BYTE_BASE + b`.
+const RAW_BYTE_BASE: u32 = 0x10_ff00;
 
 fn is_backspace_or_delete(k: &EditorKey) -> bool {
     match *k {
         EditorKey::Delete => true,
-        EditorKey::Char(c) if c == BACKSPACE || c == ctrl_key(b'h') => true,
+        EditorKey::Char(c) if c == BACKSPACE || c == ctrl_key('h') => true,
         _ => false,
     }
 }
@@ -73,20 +102,26 @@ fn is_backspace_or_delete(k: &EditorKey) -> bool {
 bitflags! {
     struct HL: u32 {
         const HIGHLIGHT_NUMBERS = 1;
-        const HIGHLIGHT_STRINGS = 1<<1;
-
-        const C_FLAGS = Self::HIGHLIGHT_NUMBERS.bits | Self::HIGHLIGHT_STRINGS.bits;
+        // A ' starts a char literal only if it's closed like one, otherwise it's a lifetime
+        const CHAR_LITERALS = 1<<1;
+        // Where a single line comment may start
+        const COMMENT_AFTER_SEPARATOR = 1<<2;
+        const COMMENT_AT_LINE_START = 1<<3;
     }
 }
 
 /*** data ***/
 
 struct EditorSyntax {
-    filetype: &'static str,
-    filematch: &'static [&'static str],
-    keywords: &'static [&'static str],
-    singleline_comment_start: Option<&'static str>,
-    multiline_comment: Option<(&'static str, &'static str)>,
+    filetype: String,
+    filematch: Vec<String>,
+    keywords: Vec<(String, Highlight)>,
+    singleline_comment_start: Option<String>,
+    multiline_comment: Option<(String, String)>,
+    // Quotes of strings with backslash escapes that end with the line
+    string_quotes: Vec<char>,
+    // Start and end of strings that may span lines, and the escape character if they have one
+    multiline_strings: Vec<(String, String, Option<char>)>,
     flags: HL,
 }
 
@@ -97,9 +132,12 @@ enum Highlight {
     MLComment,
     Keyword1,
     Keyword2,
+    Keyword3,
     String,
     Number,
     Match,
+    CurrentMatch,
+    Selection,
 }
 
 struct ERow {
@@ -108,58 +146,449 @@ struct ERow {
     render: String,
     hl: Vec<Highlight>,
     hl_open_comment: bool,
+    // The multiline string still open at the end of the row
+    hl_open_string: Option<usize>,
+}
+
+impl ERow {
+    /// Length of `chars` in characters; this is the unit `cx` is measured in.
+    fn len(&self) -> usize {
+        self.chars.chars().count()
+    }
+
+    /// Grapheme clusters of `chars`, each with the character index it starts at.
+    fn graphemes(&self) -> impl Iterator<Item = (usize, &str)> {
+        let mut cx = 0;
+        self.chars.graphemes(true).map(move |g| {
+            let at = cx;
+            cx += g.chars().count();
+            (at, g)
+        })
+    }
+
+    /// Start of the grapheme cluster before the one at `cx`.
+    fn prev_boundary(&self, cx: usize) -> usize {
+        self.graphemes()
+            .map(|(at, _)| at)
+            .take_while(|at| *at < cx)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Start of the grapheme cluster after the one at `cx`.
+    fn next_boundary(&self, cx: usize) -> usize {
+        self.graphemes()
+            .map(|(at, _)| at)
+            .find(|at| *at > cx)
+            .unwrap_or_else(|| self.len())
+    }
+
+    /// Moves `cx` back to the start of the grapheme cluster it points into.
+    fn snap_to_boundary(&self, cx: usize) -> usize {
+        if cx >= self.len() {
+            return self.len();
+        }
+        self.graphemes()
+            .map(|(at, _)| at)
+            .take_while(|at| *at <= cx)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Byte offset into `chars` of the character at index `cx`.
+    fn byte_idx(&self, cx: usize) -> usize {
+        self.chars
+            .char_indices()
+            .nth(cx)
+            .map(|(i, _)| i)
+            .unwrap_or_else(|| self.chars.len())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+}
+
+/// A single primitive buffer mutation, as recorded by the row operations.
+#[derive(Clone)]
+enum EditOp {
+    InsertChar { row: usize, at: usize, c: char },
+    DelChar { row: usize, at: usize, c: char },
+    InsertRow { at: usize, s: String },
+    DelRow { at: usize, s: String },
+    AppendString { row: usize, at: usize, s: String },
+    Truncate { row: usize, at: usize, s: String },
+    SetLineEnding { from: LineEnding, to: LineEnding },
+}
+
+impl EditOp {
+    fn inverse(&self) -> EditOp {
+        match self.clone() {
+            EditOp::InsertChar { row, at, c } => EditOp::DelChar { row, at, c },
+            EditOp::DelChar { row, at, c } => EditOp::InsertChar { row, at, c },
+            EditOp::InsertRow { at, s } => EditOp::DelRow { at, s },
+            EditOp::DelRow { at, s } => EditOp::InsertRow { at, s },
+            EditOp::AppendString { row, at, s } => EditOp::Truncate { row, at, s },
+            EditOp::Truncate { row, at, s } => EditOp::AppendString { row, at, s },
+            EditOp::SetLineEnding { from, to } => EditOp::SetLineEnding { from: to, to: from },
+        }
+    }
+}
+
+/// Everything one keypress (or one run of typing) did to the buffer.
+struct UndoStep {
+    id: u64,
+    ops: Vec<EditOp>,
+    cursor_before: (usize, usize),
+    cursor_after: (usize, usize),
+}
+
+struct UndoState {
+    undo_stack: Vec<UndoStep>,
+    redo_stack: Vec<UndoStep>,
+    pending: Vec<EditOp>,
+    recording: bool,
+    // Whether the next run of typing may be merged into the top of `undo_stack`
+    merge_typing: bool,
+    next_id: u64,
+    // Id of the top of `undo_stack` when the file was last saved (0 = empty stack)
+    saved_id: u64,
+}
+
+impl UndoState {
+    fn new() -> UndoState {
+        UndoState {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            pending: Vec::new(),
+            recording: true,
+            merge_typing: false,
+            next_id: 1,
+            saved_id: 0,
+        }
+    }
+
+    fn current_id(&self) -> u64 {
+        self.undo_stack.last().map(|s| s.id).unwrap_or(0)
+    }
+}
+
+/// What the file looked like on disk when we last read or wrote it.
+#[derive(PartialEq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    ino: u64,
+}
+
+impl FileStamp {
+    fn of(filename: &str) -> Option<FileStamp> {
+        std::fs::metadata(filename).ok().map(|meta| FileStamp {
+            modified: meta.modified().ok(),
+            len: meta.len(),
+            ino: meta.ino(),
+        })
+    }
+}
+
+/// What the previous command did, for commands that continue it.
+enum LastCommand {
+    Other,
+    Kill,
+    // Text from the kill ring was inserted at `start`, and the cursor is at its end
+    Yank { start: (usize, usize), index: usize },
+    // Asked to quit, but there were unsaved changes
+    Quit,
+}
+
+/// Something keys can be bound to in the keymap.
+struct EditorCommand {
+    name: &'static str,
+    run: fn(&mut EditorConfig, &LastCommand),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CaseMode {
+    // Case-insensitive unless the query has an uppercase letter
+    Smart,
+    Sensitive,
+    Insensitive,
+}
+
+/// What a prompt asks for. Each kind has its own history.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum PromptKind {
+    Search,
+    Replacement,
+    File,
+    Buffer,
+}
+
+impl PromptKind {
+    const ALL: [PromptKind; 4] = [
+        PromptKind::Search,
+        PromptKind::Replacement,
+        PromptKind::File,
+        PromptKind::Buffer,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            PromptKind::Search => "search",
+            PromptKind::Replacement => "replacement",
+            PromptKind::File => "file",
+            PromptKind::Buffer => "buffer",
+        }
+    }
 }
 
+/// Where a match starts and ends, as (row, byte offset into `chars`).
+type Span = ((usize, usize), (usize, usize));
+
 struct FindState {
-    last_match: isize,
-    direction: i8,
-    saved_hl_line: usize,
-    saved_hl: Option<Vec<Highlight>>,
+    // Matches of the search being typed, and the query they're for
+    matches: Vec<Span>,
+    query: String,
+    current: Option<usize>,
+    // Cursor when the search started; the first match shown is the next one from here
+    origin: (usize, usize),
+    // The match the user is asked about, highlighted differently from the others
+    current_match: Option<Span>,
+    // Compiled last search and its whole-word option, highlighted while prompting, or all the
+    // time with `highlight`
+    last_search: Option<(Regex, bool)>,
+    prompting: bool,
+    highlight: bool,
+    // Search options, kept for later searches
+    regex: bool,
+    case: CaseMode,
+    whole_word: bool,
+}
+
+/// Everything that belongs to one open file.
+struct Buffer {
+    rows: Vec<ERow>,
+    dirty: bool,
+    filename: Option<String>,
+    line_ending: LineEnding,
+    final_newline: bool,
+    syntax: Option<Rc<EditorSyntax>>,
+    undo: UndoState,
+    // Undo state id and time of the last swap file write
+    swap_id: u64,
+    swap_time: Instant,
+    // Writes of the swap file that failed in a row; reported once, and retried less often
+    swap_failures: u32,
+    disk_stamp: Option<FileStamp>,
+    disk_change_reported: bool,
+    // Start of the selection (cx, cy); the cursor is the other end
+    mark: Option<(usize, usize)>,
+    // Where the cursor was when the buffer was last shown
+    cx: usize,
+    cy: usize,
+    rowoff: usize,
+    coloff: usize,
+}
+
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer {
+            rows: Vec::new(),
+            dirty: false,
+            filename: None,
+            line_ending: LineEnding::Lf,
+            final_newline: true,
+            syntax: None,
+            undo: UndoState::new(),
+            swap_id: 0,
+            swap_time: Instant::now(),
+            swap_failures: 0,
+            disk_stamp: None,
+            disk_change_reported: false,
+            mark: None,
+            cx: 0,
+            cy: 0,
+            rowoff: 0,
+            coloff: 0,
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.filename.as_deref().unwrap_or("[No Name]")
+    }
+}
+
+/// A view of a buffer on a part of the screen. `height` doesn't include the status bar below.
+#[derive(Clone, Copy)]
+struct Window {
+    buffer: usize,
+    cx: usize,
+    cy: usize,
+    rx: usize,
+    rowoff: usize,
+    coloff: usize,
+    top: usize,
+    left: usize,
+    height: usize,
+    width: usize,
+}
+
+/// How windows share the screen. `Split`s are either side by side (`vertical`) or stacked, and
+/// `first_size` is the number of columns / lines (including its status bar) the first one gets.
+enum Layout {
+    Window(usize),
+    Split {
+        vertical: bool,
+        first_size: usize,
+        first: Box<Layout>,
+        second: Box<Layout>,
+    },
 }
 
 struct EditorConfig {
+    term_rows: usize,
+    term_cols: usize,
+    // Geometry and view of the current window; `windows[current_window]` is only updated
+    // from these when switching away from it or drawing
+    screentop: usize,
+    screenleft: usize,
     screenrows: usize,
     screencols: usize,
     cx: usize,
     rx: usize,
     cy: usize,
-    rows: Vec<ERow>,
-    dirty: bool,
     quit_times: u8,
+    // Settings from the config file
+    tab_stop: usize,
+    confirm_quit_times: u8,
+    status_timeout: Duration,
+    colors: BTreeMap<Highlight, u8>,
+    keymap: BTreeMap<Vec<EditorKey>, &'static EditorCommand>,
+    // Definitions from the syntax directory first, then the built-in ones
+    syntaxes: Vec<Rc<EditorSyntax>>,
     rowoff: usize,
     coloff: usize,
-    filename: Option<String>,
+    // The buffer being edited. Its slot in `buffers` holds a placeholder while it's out here.
+    buf: Buffer,
+    buffers: Vec<Buffer>,
+    current: usize,
+    windows: Vec<Window>,
+    current_window: usize,
+    layout: Layout,
+    // Columns between side by side windows: (top, column, height)
+    separators: Vec<(usize, usize, usize)>,
+    // Most recent kill last
+    kill_ring: Vec<String>,
+    last_command: LastCommand,
+    osc52: bool,
+    // Input that arrived while waiting for the terminal to answer a query
+    pending_input: VecDeque<u8>,
+    clipboard_copy_command: Option<String>,
+    clipboard_paste_command: Option<String>,
+    backup_files: bool,
     statusmsg: String,
     statusmsg_time: Instant,
     find: FindState,
-    syntax: Option<&'static EditorSyntax>,
+    // Shown after the input of the current prompt, set by its callback
+    prompt_hint: String,
+    // Column of the cursor on the message bar while prompting
+    prompt_cursor: Option<usize>,
+    // Earlier answers to prompts, oldest first
+    history: HashMap<PromptKind, Vec<String>>,
+    // Waiting for the first key of a command, not in a prompt or a longer binding, so checks
+    // done while idle may use the status bar
+    awaiting_command: bool,
 }
 
 impl EditorConfig {
     fn from_env() -> Result<EditorConfig> {
         let (rows, cols) = get_window_size()?;
-        Ok(EditorConfig {
+        Ok(EditorConfig::new(rows, cols))
+    }
+
+    fn new(rows: usize, cols: usize) -> EditorConfig {
+        EditorConfig {
+            term_rows: rows,
+            term_cols: cols,
+            screentop: 0,
+            screenleft: 0,
             screenrows: rows - 2,
             screencols: cols,
             cx: 0,
             rx: 0,
             cy: 0,
-            rows: Vec::new(),
-            dirty: false,
-            quit_times: 3,
+            quit_times: BYOTE_QUIT_TIMES,
+            tab_stop: BYOTE_TAB_STOP,
+            confirm_quit_times: BYOTE_QUIT_TIMES,
+            status_timeout: Duration::from_secs(BYOTE_STATUS_TIMEOUT_SECS),
+            colors: BTreeMap::new(),
+            keymap: default_keymap(),
+            syntaxes: Vec::new(),
             rowoff: 0,
             coloff: 0,
-            filename: None,
+            buf: Buffer::new(),
+            buffers: vec![Buffer::new()],
+            current: 0,
+            windows: vec![Window {
+                buffer: 0,
+                cx: 0,
+                cy: 0,
+                rx: 0,
+                rowoff: 0,
+                coloff: 0,
+                top: 0,
+                left: 0,
+                height: rows - 2,
+                width: cols,
+            }],
+            current_window: 0,
+            layout: Layout::Window(0),
+            separators: Vec::new(),
+            kill_ring: Vec::new(),
+            last_command: LastCommand::Other,
+            osc52: BYOTE_OSC52,
+            pending_input: VecDeque::new(),
+            clipboard_copy_command: BYOTE_CLIPBOARD_COPY_COMMAND.map(String::from),
+            clipboard_paste_command: BYOTE_CLIPBOARD_PASTE_COMMAND.map(String::from),
+            backup_files: BYOTE_BACKUP_FILES,
             statusmsg: String::new(),
             statusmsg_time: Instant::now(),
             find: FindState {
-                last_match: -1,
-                direction: 1,
-                saved_hl_line: 0,
-                saved_hl: None,
+                matches: Vec::new(),
+                query: String::new(),
+                current: None,
+                origin: (0, 0),
+                current_match: None,
+                last_search: None,
+                prompting: false,
+                highlight: false,
+                regex: false,
+                case: CaseMode::Smart,
+                whole_word: false,
             },
-            syntax: None,
-        })
+            prompt_hint: String::new(),
+            prompt_cursor: None,
+            history: HashMap::new(),
+            awaiting_command: false,
+        }
     }
 }
 
@@ -174,52 +603,312 @@ lazy_static! {
 
 /*** filetypes ***/
 
-static HLDB: [EditorSyntax; 1] = [EditorSyntax {
-    filetype: "c",
-    filematch: &[".c", ".h", ".cpp"],
-    keywords: &[
-        // Keyword1
-        "switch",
-        "if",
-        "while",
-        "for",
-        "break",
-        "continue",
-        "return",
-        "else",
-        "struct",
-        "union",
-        "typedef",
-        "static",
-        "enum",
-        "class",
-        "case",
-        // Keyword2
-        "int|",
-        "long|",
-        "double|",
-        "float|",
-        "char|",
-        "unsigned|",
-        "signed|",
-        "void|",
-    ],
-    singleline_comment_start: Some("//"),
-    multiline_comment: Some(("/*", "*/")),
-    flags: HL::C_FLAGS,
-}];
+/// Definitions that come with the editor, in the format of the files in the syntax directory.
+const BUILTIN_SYNTAXES: &[&str] = &[
+    r##"
+filetype = "c"
+filematch = [".c", ".h", ".cpp"]
+keywords1 = [
+    "switch", "if", "while", "for", "break", "continue", "return", "else",
+    "struct", "union", "typedef", "static", "enum", "class", "case",
+]
+keywords2 = ["int", "long", "double", "float", "char", "unsigned", "signed", "void"]
+singleline_comment = "//"
+multiline_comment = ["/*", "*/"]
+strings = ["\"", "'"]
+highlight_numbers = true
+"##,
+    r###"
+filetype = "rust"
+filematch = [".rs"]
+keywords1 = [
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+    "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+    "pub", "ref", "return", "static", "struct", "trait", "type", "unsafe", "use", "where",
+    "while",
+]
+keywords2 = [
+    "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+    "f32", "f64", "bool", "char", "str", "String", "Vec", "Option", "Result", "Box", "Self",
+]
+keywords3 = ["true", "false", "self", "super", "None", "Some", "Ok", "Err"]
+singleline_comment = "//"
+multiline_comment = ["/*", "*/"]
+multiline_strings = [
+    ["\"", "\"", "\\"], ["r\"", "\""], ["r#\"", "\"#"], ["r##\"", "\"##"],
+    ["br\"", "\""], ["br#\"", "\"#"], ["br##\"", "\"##"],
+]
+char_literals = true
+highlight_numbers = true
+"###,
+    r##"
+filetype = "python"
+filematch = [".py", ".pyw"]
+keywords1 = [
+    "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+    "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is",
+    "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with",
+    "yield",
+]
+keywords2 = ["bool", "bytes", "dict", "float", "int", "list", "object", "set", "str", "tuple"]
+keywords3 = ["True", "False", "None", "self", "cls"]
+singleline_comment = "#"
+strings = ["\"", "'"]
+multiline_strings = [["\"\"\"", "\"\"\""], ["'''", "'''"]]
+highlight_numbers = true
+"##,
+    r##"
+filetype = "go"
+filematch = [".go"]
+keywords1 = [
+    "break", "case", "chan", "const", "continue", "default", "defer", "else", "fallthrough",
+    "for", "func", "go", "goto", "if", "import", "interface", "map", "package", "range",
+    "return", "select", "struct", "switch", "type", "var",
+]
+keywords2 = [
+    "any", "bool", "byte", "complex64", "complex128", "error", "float32", "float64", "int",
+    "int8", "int16", "int32", "int64", "rune", "string", "uint", "uint8", "uint16", "uint32",
+    "uint64", "uintptr",
+]
+keywords3 = ["true", "false", "nil", "iota"]
+singleline_comment = "//"
+multiline_comment = ["/*", "*/"]
+strings = ["\"", "'"]
+multiline_strings = [["`", "`"]]
+highlight_numbers = true
+"##,
+    r##"
+filetype = "javascript"
+filematch = [".js", ".mjs", ".cjs", ".jsx"]
+keywords1 = [
+    "async", "await", "break", "case", "catch", "class", "const", "continue", "debugger",
+    "default", "delete", "do", "else", "export", "extends", "finally", "for", "function", "if",
+    "import", "in", "instanceof", "let", "new", "of", "return", "static", "super", "switch",
+    "this", "throw", "try", "typeof", "var", "void", "while", "with", "yield",
+]
+keywords2 = [
+    "Array", "Boolean", "Date", "Error", "Function", "Map", "Number", "Object", "Promise",
+    "RegExp", "Set", "String", "Symbol",
+]
+keywords3 = ["true", "false", "null", "undefined", "NaN", "Infinity"]
+singleline_comment = "//"
+multiline_comment = ["/*", "*/"]
+strings = ["\"", "'"]
+multiline_strings = [["`", "`"]]
+highlight_numbers = true
+"##,
+    r##"
+filetype = "shell"
+filematch = [".sh", ".bash", ".zsh", ".bashrc", ".profile"]
+keywords1 = [
+    "if", "then", "else", "elif", "fi", "case", "esac", "for", "select", "while", "until", "do",
+    "done", "in", "function", "return", "break", "continue", "exit", "local", "export",
+    "readonly", "declare", "unset", "shift", "source",
+]
+keywords2 = ["cd", "echo", "eval", "exec", "printf", "read", "set", "test", "trap"]
+keywords3 = ["true", "false"]
+singleline_comment = "#"
+comment_after_separator = true
+strings = ["\""]
+multiline_strings = [["'", "'"]]
+"##,
+    r##"
+filetype = "toml"
+filematch = [".toml", "Cargo.lock"]
+keywords3 = ["true", "false"]
+singleline_comment = "#"
+strings = ["\""]
+multiline_strings = [["\"\"\"", "\"\"\"", "\\"], ["'''", "'''"], ["'", "'"]]
+highlight_numbers = true
+"##,
+    // Headings are shown like comments, code like strings
+    r##"
+filetype = "markdown"
+filematch = [".md", ".markdown"]
+singleline_comment = "#"
+comment_at_line_start = true
+strings = ["`"]
+multiline_strings = [["```", "```"]]
+"##,
+];
+
+const MULTILINE_STRINGS_ERROR: &str =
+    "`multiline_strings` must be an array of starts and ends, each with an optional escape";
+
+fn syntax_set(
+    syntax: &mut EditorSyntax,
+    table: &str,
+    name: &str,
+    value: &ConfigValue,
+) -> Result<()> {
+    if !table.is_empty() {
+        bail!(format!("unknown option `{}.{}`", table, name));
+    }
+    match name {
+        "filetype" => syntax.filetype = config_string(name, value)?,
+        "filematch" => syntax.filematch = config_strings(name, value)?,
+        "keywords1" | "keywords2" | "keywords3" => {
+            let hl = match name {
+                "keywords1" => Highlight::Keyword1,
+                "keywords2" => Highlight::Keyword2,
+                _ => Highlight::Keyword3,
+            };
+            let keywords = config_strings(name, value)?;
+            if keywords.iter().any(String::is_empty) {
+                bail!(format!("`{}` can't have an empty keyword", name));
+            }
+            syntax
+                .keywords
+                .extend(keywords.into_iter().map(|k| (k, hl.clone())));
+        }
+        "singleline_comment" => {
+            syntax.singleline_comment_start =
+                Some(config_string(name, value)?).filter(|s| !s.is_empty())
+        }
+        "multiline_comment" => match config_strings(name, value)?.as_slice() {
+            [start, end] if !start.is_empty() && !end.is_empty() => {
+                syntax.multiline_comment = Some((start.clone(), end.clone()))
+            }
+            _ => bail!("`multiline_comment` must be a start and an end"),
+        },
+        "highlight_numbers" => syntax
+            .flags
+            .set(HL::HIGHLIGHT_NUMBERS, config_bool(name, value)?),
+        "char_literals" => syntax
+            .flags
+            .set(HL::CHAR_LITERALS, config_bool(name, value)?),
+        "comment_after_separator" => syntax
+            .flags
+            .set(HL::COMMENT_AFTER_SEPARATOR, config_bool(name, value)?),
+        "comment_at_line_start" => syntax
+            .flags
+            .set(HL::COMMENT_AT_LINE_START, config_bool(name, value)?),
+        // What highlight_strings meant before quotes could be chosen
+        "highlight_strings" => {
+            syntax.string_quotes = match config_bool(name, value)? {
+                true => vec!['"', '\''],
+                false => Vec::new(),
+            }
+        }
+        "strings" => {
+            syntax.string_quotes.clear();
+            for quote in config_strings(name, value)? {
+                let mut chars = quote.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => syntax.string_quotes.push(c),
+                    _ => bail!("`strings` must be an array of quote characters"),
+                }
+            }
+        }
+        "multiline_strings" => {
+            let pairs = match value {
+                ConfigValue::Array(items) => items
+                    .iter()
+                    .map(|item| config_strings(name, item))
+                    .collect::<Result<Vec<_>>>()?,
+                _ => bail!(MULTILINE_STRINGS_ERROR),
+            };
+            syntax.multiline_strings.clear();
+            for pair in pairs {
+                let (start, end, escape) = match pair.as_slice() {
+                    [start, end] => (start, end, None),
+                    [start, end, escape] => {
+                        let mut chars = escape.chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(c), None) => (start, end, Some(c)),
+                            _ => bail!(MULTILINE_STRINGS_ERROR),
+                        }
+                    }
+                    _ => bail!(MULTILINE_STRINGS_ERROR),
+                };
+                if start.is_empty() || end.is_empty() {
+                    bail!(MULTILINE_STRINGS_ERROR);
+                }
+                syntax
+                    .multiline_strings
+                    .push((start.clone(), end.clone(), escape));
+            }
+        }
+        _ => bail!(format!("unknown option `{}`", name)),
+    }
+    Ok(())
+}
+
+/// Parses a syntax definition. It needs at least a `filetype` and a `filematch`.
+fn parse_syntax(data: &str, path: &Path) -> std::result::Result<EditorSyntax, Vec<String>> {
+    let mut syntax = EditorSyntax {
+        filetype: String::new(),
+        filematch: Vec::new(),
+        keywords: Vec::new(),
+        singleline_comment_start: None,
+        multiline_comment: None,
+        string_quotes: Vec::new(),
+        multiline_strings: Vec::new(),
+        flags: HL::empty(),
+    };
+    let mut errors = parse_config(data, path, |table, name, value| {
+        syntax_set(&mut syntax, table, name, &value)
+    });
+    if syntax.filetype.is_empty() || syntax.filematch.is_empty() {
+        errors.push(format!(
+            "{}: needs a filetype and a filematch",
+            path.display()
+        ));
+    }
+    if errors.is_empty() {
+        Ok(syntax)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Loads the `*.toml` files in the syntax directory and the built-in definitions. A file with
+/// the filetype of a built-in definition replaces it. Returns the problems with the files.
+fn editor_syntax_load(e: &mut EditorConfig) -> Vec<String> {
+    let mut syntaxes = Vec::new();
+    let mut errors = Vec::new();
+    if let Some(dir) = config_dir().map(|dir| dir.join("syntax")) {
+        let mut paths: Vec<PathBuf> = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .collect(),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                errors.push(format!("Can't read {}: {}", dir.display(), err));
+                Vec::new()
+            }
+        };
+        paths.sort();
+        for path in paths {
+            match std::fs::read_to_string(&path).map(|data| parse_syntax(&data, &path)) {
+                Ok(Ok(syntax)) => syntaxes.push(syntax),
+                Ok(Err(syntax_errors)) => errors.extend(syntax_errors),
+                Err(err) => errors.push(format!("Can't read {}: {}", path.display(), err)),
+            }
+        }
+    }
+    for data in BUILTIN_SYNTAXES {
+        let syntax = parse_syntax(data, Path::new("built-in syntax")).unwrap();
+        if !syntaxes.iter().any(|s| s.filetype == syntax.filetype) {
+            syntaxes.push(syntax);
+        }
+    }
+    e.syntaxes = syntaxes.into_iter().map(Rc::new).collect();
+    errors
+}
 
 /*** terminal ***/
 
 #[allow(clippy::print_with_newline)]
 fn die(s: &str) {
-    print!("\x1b[2J\x1b[H{}: {}\r\n", s, Error::last().to_string());
+    print!("\x1b[2J\x1b[H{}: {}\r\n", s, Error::last());
     flush_stdout();
     exit(1);
 }
 
 extern "C" fn disable_raw_mode() {
-    if tcsetattr(*STDIN_RAWFD, TCSAFLUSH, &*ORIG_TERMIOS).is_err() {
+    if tcsetattr(*STDIN_RAWFD, TCSAFLUSH, &ORIG_TERMIOS).is_err() {
         die("disable_raw_mode/tcsetattr");
     }
 }
@@ -241,32 +930,42 @@ fn enable_raw_mode() {
     }
 }
 
-fn editor_read_key() -> EditorKey {
+/// Reads what's available of the input, starting with what was put aside earlier.
+fn editor_read_input(e: &mut EditorConfig, buffer: &mut [u8]) -> std::io::Result<usize> {
+    if e.pending_input.is_empty() {
+        return std::io::stdin().read(buffer);
+    }
+    let n = buffer.len().min(e.pending_input.len());
+    for (i, b) in e.pending_input.drain(..n).enumerate() {
+        buffer[i] = b;
+    }
+    Ok(n)
+}
+
+fn editor_read_key(e: &mut EditorConfig) -> EditorKey {
     let mut buffer: [u8; 1] = [0];
     loop {
-        match std::io::stdin().read(&mut buffer) {
+        match editor_read_input(e, &mut buffer) {
             Err(ref e) if e.kind() != ErrorKind::Interrupted => die("editor_read_key/read"),
             Err(e) => die(&format!("editor_read_key: {}", e)),
             Ok(1) => {
                 let c = buffer[0];
                 if c == b'\x1b' {
-                    let mut buffer = [0_u8; 3];
-
-                    // I think we're potentially losing a character or two here? If there's a char
-                    // after the escape, but not matching what we expect?
-
-                    let seq = std::io::stdin()
-                        .read(&mut buffer)
-                        .map_err(Box::new)
-                        .map(|n| &buffer[..n]);
-                    if seq.is_err() {
-                        return EditorKey::Escape;
+                    // Only as many bytes as the sequence needs, what follows is the next key
+                    let mut seq = Vec::with_capacity(3);
+                    while let Some(b) = editor_read_seq_byte(e) {
+                        seq.push(b);
+                        let done = match seq.as_slice() {
+                            [b'[' | b'O'] => false,
+                            [b'[', b] => !b.is_ascii_digit(),
+                            _ => true,
+                        };
+                        if done {
+                            break;
+                        }
                     }
 
-                    let seq_str = unwrap_or_die(
-                        "editor_read_key/from_utf8",
-                        std::str::from_utf8(seq.unwrap()),
-                    );
+                    let seq_str = std::str::from_utf8(&seq).unwrap_or("");
 
                     return match seq_str {
                         "[A" => EditorKey::ArrowUp,
@@ -280,15 +979,19 @@ fn editor_read_key() -> EditorKey {
                         "[1~" | "[7~" | "[H" | "OH" => EditorKey::Home,
                         "[4~" | "[8~" | "[F" | "OF" => EditorKey::End,
 
+                        s if s.len() == 1 && s != "[" && s != "O" => {
+                            EditorKey::Alt(s.chars().next().unwrap())
+                        }
+
                         _ => EditorKey::Escape,
                     };
                 } else if c == b'\r' {
                     return EditorKey::Return;
-                } else {
+                } else if let Some(c) = editor_read_utf8(e, c) {
                     return EditorKey::Char(c);
                 }
             }
-            Ok(0) => (),
+            Ok(0) => editor_idle(e),
             Ok(n) => die(&format!(
                 "editor_read_key read unexpected number of chars: {}",
                 n
@@ -297,6 +1000,36 @@ fn editor_read_key() -> EditorKey {
     }
 }
 
+/// Reads the next byte of an escape sequence, if it comes in time.
+fn editor_read_seq_byte(e: &mut EditorConfig) -> Option<u8> {
+    let mut buffer = [0_u8; 1];
+    match editor_read_input(e, &mut buffer) {
+        Ok(1) => Some(buffer[0]),
+        _ => None,
+    }
+}
+
+/// Reads the continuation bytes of a UTF-8 sequence starting with `first`. Invalid input
+/// is dropped.
+fn editor_read_utf8(e: &mut EditorConfig, first: u8) -> Option<char> {
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return None,
+    };
+    let mut buffer = [first, 0, 0, 0];
+    for i in 1..len {
+        if !matches!(editor_read_input(e, &mut buffer[i..=i]), Ok(1)) {
+            return None;
+        }
+    }
+    std::str::from_utf8(&buffer[..len])
+        .ok()
+        .and_then(|s| s.chars().next())
+}
+
 fn get_cursor_position() -> Result<(usize, usize)> {
     std::io::stdout().write_all(b"\x1b[6n\r\n")?;
     flush_stdout();
@@ -353,35 +1086,92 @@ fn is_separator(c: char) -> bool {
     c.is_whitespace() || c == '\0' || ",.()+-/*=~%<>[];".contains(c)
 }
 
-fn editor_update_syntax(e: &mut EditorConfig, at_row: usize) {
-    let row = &mut e.rows[at_row];
-    row.hl = vec![Highlight::Normal; row.render.len()];
+fn starts_with_at(s: &[char], at: usize, pat: &str) -> bool {
+    pat.chars()
+        .enumerate()
+        .all(|(i, c)| s.get(at + i) == Some(&c))
+}
 
-    if e.syntax.is_none() {
-        return;
+/// Length of the char literal starting with the `'` at `at`, if it is one rather than a
+/// lifetime or label.
+fn char_literal_len(render: &[char], at: usize) -> Option<usize> {
+    match render.get(at + 1) {
+        // Escapes like '\n', '\'' or '\u{1F600}'
+        Some('\\') => (at + 3..render.len().min(at + 12))
+            .find(|&i| render[i] == '\'')
+            .map(|i| i - at + 1),
+        Some(_) if render.get(at + 2) == Some(&'\'') => Some(3),
+        _ => None,
+    }
+}
+
+/// Moves past the rest of a token of `len` characters whose first one was just read.
+fn skip_rest<I: Iterator>(iter: &mut I, len: usize) {
+    if len > 1 {
+        iter.nth(len - 2);
     }
-    let syntax = e.syntax.unwrap();
-    let keywords = syntax.keywords;
+}
+
+fn editor_update_syntax(e: &mut EditorConfig, at_row: usize) {
+    let row = &mut e.buf.rows[at_row];
+    let render: Vec<char> = row.render.chars().collect();
+    row.hl = vec![Highlight::Normal; render.len()];
+
+    let syntax = match &e.buf.syntax {
+        Some(syntax) => Rc::clone(syntax),
+        None => return,
+    };
 
-    let scs = syntax.singleline_comment_start.unwrap_or("");
-    let (mcs, mce) = syntax.multiline_comment.unwrap_or(("", ""));
+    let scs = syntax.singleline_comment_start.as_deref().unwrap_or("");
+    let (mcs, mce) = syntax
+        .multiline_comment
+        .as_ref()
+        .map_or(("", ""), |(mcs, mce)| (mcs.as_str(), mce.as_str()));
 
-    let scs_len = scs.len();
-    let mcs_len = mcs.len();
-    let mce_len = mce.len();
+    let scs_len = scs.chars().count();
+    let mcs_len = mcs.chars().count();
+    let mce_len = mce.chars().count();
 
     let mut prev_sep: bool = true;
     let mut in_string: char = '\0';
 
-    let row = &e.rows[at_row];
-    let mut in_comment: bool = row.idx > 0 && e.rows[row.idx - 1].hl_open_comment;
-    let row = &mut e.rows[at_row];
+    let row = &e.buf.rows[at_row];
+    let (mut in_comment, mut in_ml_string) = match row.idx {
+        0 => (false, None),
+        idx => {
+            let prev = &e.buf.rows[idx - 1];
+            (prev.hl_open_comment, prev.hl_open_string)
+        }
+    };
+    let row = &mut e.buf.rows[at_row];
 
-    let mut iter = row.render.char_indices().peekable();
+    let mut iter = render.iter().cloned().enumerate().peekable();
     let mut prev_hl = Highlight::Normal;
-
     while let Some((i, c)) = iter.next() {
-        if scs_len > 0 && in_string == '\0' && !in_comment && row.render[i..].starts_with(scs) {
+        if let Some(s) = in_ml_string {
+            row.hl[i] = Highlight::String;
+            let (_, end, escape) = &syntax.multiline_strings[s];
+            if Some(c) == *escape && iter.peek().is_some() {
+                row.hl[iter.next().unwrap().0] = Highlight::String;
+                continue;
+            }
+            if starts_with_at(&render, i, end) {
+                let len = end.chars().count();
+                row.hl[i..i + len].fill(Highlight::String);
+                skip_rest(&mut iter, len);
+                in_ml_string = None;
+                prev_sep = true;
+            }
+            continue;
+        }
+
+        if scs_len > 0
+            && in_string == '\0'
+            && !in_comment
+            && starts_with_at(&render, i, scs)
+            && (!syntax.flags.contains(HL::COMMENT_AFTER_SEPARATOR) || prev_sep)
+            && (!syntax.flags.contains(HL::COMMENT_AT_LINE_START) || i == 0)
+        {
             let comment_len = row.hl.len() - i;
             row.hl
                 .splice(i..i + comment_len, vec![Highlight::Comment; comment_len]);
@@ -391,44 +1181,69 @@ fn editor_update_syntax(e: &mut EditorConfig, at_row: usize) {
         if mcs_len > 0 && mce_len > 0 && in_string == '\0' {
             if in_comment {
                 row.hl[i] = Highlight::MLComment;
-                if row.render[i..].starts_with(mce) {
+                if starts_with_at(&render, i, mce) {
                     row.hl
                         .splice(i..i + mce_len, vec![Highlight::MLComment; mce_len]);
-                    iter.nth(mce_len - 2);
+                    skip_rest(&mut iter, mce_len);
                     in_comment = false;
                     prev_sep = true;
                     continue;
                 } else {
                     continue;
                 }
-            } else if row.render[i..].starts_with(mcs) {
+            } else if starts_with_at(&render, i, mcs) {
                 row.hl
                     .splice(i..i + mcs_len, vec![Highlight::MLComment; mcs_len]);
-                iter.nth(mcs_len - 2);
+                skip_rest(&mut iter, mcs_len);
                 in_comment = true;
                 continue;
             }
         }
 
-        if syntax.flags.contains(HL::HIGHLIGHT_STRINGS) {
+        if in_string == '\0' {
+            // Prefixed ones like r"..." only start a word
+            let start = syntax.multiline_strings.iter().position(|(start, _, _)| {
+                starts_with_at(&render, i, start)
+                    && (prev_sep || !start.starts_with(char::is_alphanumeric))
+            });
+            if let Some(s) = start {
+                let len = syntax.multiline_strings[s].0.chars().count();
+                row.hl[i..i + len].fill(Highlight::String);
+                skip_rest(&mut iter, len);
+                in_ml_string = Some(s);
+                continue;
+            }
+        }
+
+        if !syntax.string_quotes.is_empty() {
             if in_string != '\0' {
                 row.hl[i] = Highlight::String;
                 if c == '\\' && iter.peek().is_some() {
                     row.hl[iter.next().unwrap().0] = Highlight::String;
                     continue;
                 }
-                if c == in_string as char {
+                if c == in_string {
                     in_string = '\0';
                 };
                 prev_sep = true;
                 continue;
-            } else if c == '"' || c == '\'' {
+            } else if syntax.string_quotes.contains(&c) {
                 in_string = c;
                 row.hl[i] = Highlight::String;
                 continue;
             }
         }
 
+        // Anything else starting with a quote is a lifetime or a label
+        if syntax.flags.contains(HL::CHAR_LITERALS) && c == '\'' {
+            if let Some(len) = char_literal_len(&render, i) {
+                row.hl[i..i + len].fill(Highlight::String);
+                skip_rest(&mut iter, len);
+                prev_sep = true;
+                continue;
+            }
+        }
+
         if syntax.flags.contains(HL::HIGHLIGHT_NUMBERS)
             && ((c.is_ascii_digit() && (prev_sep || prev_hl == Highlight::Number))
                 || (c == '.' && prev_hl == Highlight::Number))
@@ -441,25 +1256,13 @@ fn editor_update_syntax(e: &mut EditorConfig, at_row: usize) {
 
         if prev_sep {
             let mut keyword_found = false;
-            for spec in keywords.iter() {
-                let kw2 = spec.ends_with('|');
-                let klen = if kw2 { spec.len() - 1 } else { spec.len() };
-                let keyword = &spec[..klen];
-                if row.render[i..].starts_with(keyword)
+            for (keyword, hl) in syntax.keywords.iter() {
+                let klen = keyword.chars().count();
+                if starts_with_at(&render, i, keyword)
                     && is_separator(iter.clone().map(|t| t.1).nth(klen - 1).unwrap_or('\r'))
                 {
-                    row.hl.splice(
-                        i..i + klen,
-                        vec![
-                            if kw2 {
-                                Highlight::Keyword2
-                            } else {
-                                Highlight::Keyword1
-                            };
-                            klen
-                        ],
-                    );
-                    iter.nth(klen - 2);
+                    row.hl.splice(i..i + klen, vec![hl.clone(); klen]);
+                    skip_rest(&mut iter, klen);
                     keyword_found = true;
                     break;
                 }
@@ -474,44 +1277,48 @@ fn editor_update_syntax(e: &mut EditorConfig, at_row: usize) {
         prev_hl = row.hl[i].clone();
     }
 
-    let changed = row.hl_open_comment != in_comment;
+    let changed = row.hl_open_comment != in_comment || row.hl_open_string != in_ml_string;
     row.hl_open_comment = in_comment;
-    let row = &e.rows[at_row];
-    if changed && row.idx + 1 < e.rows.len() {
+    row.hl_open_string = in_ml_string;
+    let row = &e.buf.rows[at_row];
+    if changed && row.idx + 1 < e.buf.rows.len() {
         editor_update_syntax(e, row.idx + 1);
     }
 }
 
-fn editor_syntax_to_color(hl: &Highlight) -> u8 {
+fn editor_syntax_to_color(e: &EditorConfig, hl: &Highlight) -> u8 {
+    if let Some(&color) = e.colors.get(hl) {
+        return color;
+    }
     match hl {
-        Highlight::Number => 31,
+        Highlight::Number | Highlight::Keyword3 => 31,
         Highlight::Keyword2 => 32,
         Highlight::Keyword1 => 33,
         Highlight::Match => 34,
         Highlight::String => 35,
         Highlight::Comment | Highlight::MLComment => 36,
+        // Not colors, but inverse video
+        Highlight::CurrentMatch | Highlight::Selection => 7,
         _ => 37,
     }
 }
 
 fn editor_select_syntax_highlight(e: &mut EditorConfig) {
-    match &e.filename {
-        None => e.syntax = None,
+    match &e.buf.filename {
+        None => e.buf.syntax = None,
         Some(filename) => {
-            for syntax in HLDB.iter() {
-                for filematch in syntax.filematch.iter() {
+            let found = e.syntaxes.iter().find(|syntax| {
+                syntax.filematch.iter().any(|filematch| {
                     let is_ext = filematch.starts_with('.');
-                    if (is_ext && filename.ends_with(filematch))
-                        || (!is_ext && filename.contains(filematch))
-                    {
-                        e.syntax = Some(&syntax);
-
-                        for i in 0..e.rows.len() {
-                            editor_update_syntax(e, i);
-                        }
+                    (is_ext && filename.ends_with(filematch.as_str()))
+                        || (!is_ext && filename.contains(filematch.as_str()))
+                })
+            });
+            if let Some(syntax) = found {
+                e.buf.syntax = Some(Rc::clone(syntax));
 
-                        return;
-                    }
+                for i in 0..e.buf.rows.len() {
+                    editor_update_syntax(e, i);
                 }
             }
         }
@@ -520,47 +1327,83 @@ fn editor_select_syntax_highlight(e: &mut EditorConfig) {
 
 /*** row operations ***/
 
-fn editor_row_cx_to_rx(r: &ERow, cx: usize) -> usize {
+/// Number of terminal columns a grapheme cluster takes up when drawn by `editor_draw_rows`.
+/// Control and zero-width clusters are drawn as a single visible placeholder, raw bytes
+/// as their hex value.
+fn grapheme_width(g: &str) -> usize {
+    if g.starts_with(|c: char| c.is_ascii_control()) {
+        1
+    } else if g.starts_with(|c: char| char_to_raw_byte(c).is_some()) {
+        4
+    } else {
+        g.width().max(1)
+    }
+}
+
+fn str_width(s: &str) -> usize {
+    s.graphemes(true).map(grapheme_width).sum()
+}
+
+/// Crops `s` so that it fits into `cols` terminal columns.
+fn truncate_to_width(s: &str, cols: usize) -> String {
+    let mut width = 0;
+    s.graphemes(true)
+        .take_while(|g| {
+            width += grapheme_width(g);
+            width <= cols
+        })
+        .collect()
+}
+
+fn editor_row_cx_to_rx(r: &ERow, cx: usize, tab_stop: usize) -> usize {
     let mut rx: usize = 0;
-    for c in r.chars.chars().take(cx) {
-        rx += match c {
-            '\t' => BYOTE_TAB_STOP - (rx % BYOTE_TAB_STOP),
-            _ => 1,
+    for (_, g) in r.graphemes().take_while(|(at, _)| *at < cx) {
+        rx += match g {
+            "\t" => tab_stop - (rx % tab_stop),
+            _ => grapheme_width(g),
         }
     }
     rx
 }
 
-fn editor_row_rx_to_cx(r: &ERow, rx: usize) -> usize {
-    let mut cur_rx = 0;
-    for (cx, c) in r.render.char_indices() {
-        cur_rx += match c {
-            '\t' => BYOTE_TAB_STOP - (cur_rx % BYOTE_TAB_STOP),
-            _ => 1,
-        };
-        if cur_rx > rx {
-            return cx;
-        };
+/// Index into `render` (and `hl`) of the character at `cx`.
+fn editor_row_cx_to_render_idx(r: &ERow, cx: usize, tab_stop: usize) -> usize {
+    let mut rx = 0;
+    let mut idx = 0;
+    for (_, g) in r.graphemes().take_while(|(at, _)| *at < cx) {
+        if g == "\t" {
+            let n = tab_stop - rx % tab_stop;
+            rx += n;
+            idx += n;
+        } else {
+            rx += grapheme_width(g);
+            idx += g.chars().count();
+        }
     }
-    rx
+    idx
 }
 
 fn editor_update_row(e: &mut EditorConfig, at_row: usize) {
-    let r = &mut e.rows[at_row];
-    r.render = r
-        .chars
-        .char_indices()
-        .map(|(i, c)| match c {
-            '\t' => " ".repeat(BYOTE_TAB_STOP - i % BYOTE_TAB_STOP),
-            _ => c.to_string(),
-        })
-        .collect();
+    let tab_stop = e.tab_stop;
+    let r = &mut e.buf.rows[at_row];
+    r.render.clear();
+    let mut rx = 0;
+    for g in r.chars.graphemes(true) {
+        if g == "\t" {
+            let n = tab_stop - rx % tab_stop;
+            r.render += &" ".repeat(n);
+            rx += n;
+        } else {
+            r.render += g;
+            rx += grapheme_width(g);
+        }
+    }
 
     editor_update_syntax(e, at_row);
 }
 
 fn editor_insert_row(e: &mut EditorConfig, at: usize, s: &str) {
-    if at > e.rows.len() {
+    if at > e.buf.rows.len() {
         return;
     }
 
@@ -570,60 +1413,106 @@ fn editor_insert_row(e: &mut EditorConfig, at: usize, s: &str) {
         render: String::new(),
         hl: Vec::new(),
         hl_open_comment: false,
+        hl_open_string: None,
     };
 
-    for later_row in &mut e.rows.iter_mut().skip(at + 1) {
+    e.buf.rows.insert(at, row);
+    for later_row in &mut e.buf.rows[at + 1..] {
         later_row.idx += 1;
     }
-
-    e.rows.insert(at, row);
     editor_update_row(e, at);
 
-    e.dirty = true;
+    editor_undo_record(e, EditOp::InsertRow { at, s: s.into() });
+    e.buf.dirty = true;
 }
 
 fn editor_del_row(e: &mut EditorConfig, at: usize) {
-    if at >= e.rows.len() {
+    if at >= e.buf.rows.len() {
         // note usize can never be < 0, so not checking that
         return;
     }
-    e.rows.remove(at);
+    let row = e.buf.rows.remove(at);
 
-    for later_row in &mut e.rows[at..] {
+    for later_row in &mut e.buf.rows[at..] {
         later_row.idx -= 1;
     }
 
-    e.dirty = true;
+    editor_undo_record(e, EditOp::DelRow { at, s: row.chars });
+    e.buf.dirty = true;
 }
 
-fn editor_row_insert_char(e: &mut EditorConfig, at: usize, c: char) {
-    let row = &mut e.rows[e.cy];
-    row.chars.insert(at.max(0).min(row.chars.len()), c);
-    editor_update_row(e, e.cy);
-    e.dirty = true;
+fn editor_row_insert_char(e: &mut EditorConfig, at_row: usize, at: usize, c: char) {
+    let row = &mut e.buf.rows[at_row];
+    let at = at.min(row.len());
+    let idx = row.byte_idx(at);
+    row.chars.insert(idx, c);
+    editor_update_row(e, at_row);
+    editor_undo_record(e, EditOp::InsertChar { row: at_row, at, c });
+    e.buf.dirty = true;
 }
 
 fn editor_row_append_string(e: &mut EditorConfig, at_row: usize, s: &str) {
-    let row = &mut e.rows[at_row];
+    let row = &mut e.buf.rows[at_row];
+    let at = row.len();
     row.chars += s;
     editor_update_row(e, at_row);
-    e.dirty = true;
+    editor_undo_record(
+        e,
+        EditOp::AppendString {
+            row: at_row,
+            at,
+            s: s.into(),
+        },
+    );
+    e.buf.dirty = true;
 }
 
 fn editor_row_del_char(e: &mut EditorConfig, at_row: usize, at: usize) {
-    let row = &mut e.rows[at_row];
-    row.chars.remove(at.max(0).min(row.chars.len()));
+    let row = &mut e.buf.rows[at_row];
+    let at = at.min(row.len());
+    let idx = row.byte_idx(at);
+    let c = row.chars.remove(idx);
+    editor_update_row(e, at_row);
+    editor_undo_record(e, EditOp::DelChar { row: at_row, at, c });
+    e.buf.dirty = true;
+}
+
+fn editor_row_truncate(e: &mut EditorConfig, at_row: usize, at: usize) {
+    let row = &mut e.buf.rows[at_row];
+    let at = at.min(row.len());
+    let idx = row.byte_idx(at);
+    let s = row.chars.split_off(idx);
     editor_update_row(e, at_row);
-    e.dirty = true;
+    editor_undo_record(e, EditOp::Truncate { row: at_row, at, s });
+    e.buf.dirty = true;
+}
+
+/// Removes the rest of the row from `at`, or the line break if there is nothing left, and
+/// returns what was removed.
+fn editor_row_kill(e: &mut EditorConfig, at_row: usize, at: usize) -> String {
+    let row = &e.buf.rows[at_row];
+    let idx = row.byte_idx(at);
+    if idx < row.chars.len() {
+        let killed = row.chars[idx..].to_string();
+        editor_row_truncate(e, at_row, at);
+        killed
+    } else if at_row + 1 < e.buf.rows.len() {
+        let next = e.buf.rows[at_row + 1].chars.clone();
+        editor_row_append_string(e, at_row, &next);
+        editor_del_row(e, at_row + 1);
+        "\n".into()
+    } else {
+        String::new()
+    }
 }
 
 /*** editor operations ***/
 
 fn editor_insert_char(e: &mut EditorConfig, c: char) {
-    if e.cy == e.rows.len() {
-        editor_insert_row(e, e.rows.len(), "");
+    if e.cy == e.buf.rows.len() {
+        editor_insert_row(e, e.buf.rows.len(), "");
     }
-    editor_row_insert_char(e, e.cx, c);
+    editor_row_insert_char(e, e.cy, e.cx, c);
     e.cx += 1;
 }
 
@@ -631,142 +1520,1746 @@ fn editor_insert_new_line(e: &mut EditorConfig) {
     if e.cx == 0 {
         editor_insert_row(e, e.cy, "");
     } else {
-        let right: String = e.rows[e.cy].chars[e.cx..].into();
+        let row = &e.buf.rows[e.cy];
+        let right: String = row.chars[row.byte_idx(e.cx)..].into();
         editor_insert_row(e, e.cy + 1, &right);
-        let row = &mut e.rows[e.cy];
-        row.chars = row.chars[..e.cx].into();
-        editor_update_row(e, e.cy);
+        editor_row_truncate(e, e.cy, e.cx);
     }
     e.cy += 1;
     e.cx = 0;
 }
 
 fn editor_del_char(e: &mut EditorConfig) {
-    if e.cy == e.rows.len() {
+    if e.cy == e.buf.rows.len() {
         return;
     }
     if e.cx == 0 && e.cy == 0 {
         return;
     }
     if e.cx > 0 {
-        editor_row_del_char(e, e.cy, e.cx - 1);
-        e.cx -= 1;
+        // Delete the whole grapheme cluster, so that e.g. an accented letter typed as two
+        // code points goes away in one keypress
+        let start = e.buf.rows[e.cy].prev_boundary(e.cx);
+        while e.cx > start {
+            editor_row_del_char(e, e.cy, e.cx - 1);
+            e.cx -= 1;
+        }
     } else {
-        e.cx = e.rows[e.cy - 1].chars.len();
+        e.cx = e.buf.rows[e.cy - 1].len();
         // This is clunky due to the fact that all of `e` needs to be borrowed,
         // and we can only borrow it mutably once, and we can't mix mutable
         // and immutable borrows of it. Note that `&e.blah` tries to borrow `e` fully.
-        editor_row_append_string(e, e.cy - 1, &e.rows[e.cy].chars.clone());
+        editor_row_append_string(e, e.cy - 1, &e.buf.rows[e.cy].chars.clone());
         editor_del_row(e, e.cy);
         e.cy -= 1;
     }
 }
 
-/*** file i/o ***/
-
-fn editor_rows_to_string(e: &EditorConfig) -> String {
-    if e.rows.is_empty() {
-        return String::new();
+fn editor_set_line_ending(e: &mut EditorConfig, line_ending: LineEnding) {
+    if e.buf.line_ending == line_ending {
+        return;
     }
-    e.rows
-        .iter()
-        .skip(1)
-        .map(|r| &r.chars)
-        .fold(e.rows[0].chars.clone(), |a, b| a + "\n" + b)
+    editor_undo_record(
+        e,
+        EditOp::SetLineEnding {
+            from: e.buf.line_ending,
+            to: line_ending,
+        },
+    );
+    e.buf.line_ending = line_ending;
+    e.buf.dirty = true;
 }
 
-fn editor_open(e: &mut EditorConfig, filename: &str) {
-    e.filename = Some(filename.into());
-    editor_select_syntax_highlight(e);
+fn editor_toggle_line_ending(e: &mut EditorConfig) {
+    let line_ending = match e.buf.line_ending {
+        LineEnding::Lf => LineEnding::CrLf,
+        LineEnding::CrLf => LineEnding::Lf,
+    };
+    editor_set_line_ending(e, line_ending);
+    editor_set_status_message(
+        e,
+        &format!("Line endings converted to {}", line_ending.name()),
+    );
+}
 
-    let file = unwrap_or_die("editor_open/open", std::fs::File::open(filename));
-    let reader = std::io::BufReader::new(file);
-    for line in reader.lines() {
-        line.map(|l| editor_insert_row(e, e.rows.len(), &l))
-            .unwrap();
+/*** selection ***/
+
+/// Turns a position that may be past the last row into the equivalent one at its end.
+fn editor_clamp_pos(e: &EditorConfig, (cx, cy): (usize, usize)) -> (usize, usize) {
+    match e.buf.rows.len() {
+        0 => (0, 0),
+        len if cy >= len => (e.buf.rows[len - 1].len(), len - 1),
+        _ => (e.buf.rows[cy].snap_to_boundary(cx), cy),
     }
-    e.dirty = false;
 }
 
-fn editor_save(e: &mut EditorConfig) {
-    if e.filename.is_none() {
-        e.filename = editor_prompt(e, "Save as (ESC to cancel): ", None);
-        if e.filename.is_none() {
-            editor_set_status_message(e, "Save aborted!");
-            return;
-        }
-        editor_select_syntax_highlight(e);
+/// The selected region as (start, end) positions, start first.
+fn editor_selection(e: &EditorConfig) -> Option<((usize, usize), (usize, usize))> {
+    let mark = editor_clamp_pos(e, e.buf.mark?);
+    let point = editor_clamp_pos(e, (e.cx, e.cy));
+    if (mark.1, mark.0) <= (point.1, point.0) {
+        Some((mark, point))
+    } else {
+        Some((point, mark))
     }
+}
 
-    match &e.filename {
-        Some(filename) => {
-            let buf = editor_rows_to_string(e);
-            let msg = &std::fs::File::create(filename)
-                .and_then(|file| file.set_len(buf.len() as u64).map(|_| file))
-                .and_then(|mut file| file.write(buf.as_bytes()))
-                .map(|n| {
-                    e.dirty = false;
-                    format!("{} bytes written to disk", n)
-                })
-                .unwrap_or_else(|e| format!("Can't save! I/O error: {}", e));
-            editor_set_status_message(e, msg);
-        }
-        None => (),
+fn editor_toggle_mark(e: &mut EditorConfig) {
+    if e.buf.mark.take().is_some() {
+        editor_set_status_message(e, "Mark deactivated");
+    } else {
+        e.buf.mark = Some((e.cx, e.cy));
+        editor_set_status_message(e, "Mark set");
     }
 }
 
-/*** find ***/
-
-fn editor_find_callback(e: &mut EditorConfig, query: &str, key: &EditorKey) {
-    if let Some(saved_hl) = &e.find.saved_hl {
-        e.rows[e.find.saved_hl_line].hl = saved_hl.clone();
-        e.find.saved_hl = None;
+fn editor_region_text(
+    e: &EditorConfig,
+    (sx, sy): (usize, usize),
+    (ex, ey): (usize, usize),
+) -> String {
+    if e.buf.rows.is_empty() {
+        return String::new();
     }
+    let first = &e.buf.rows[sy];
+    if sy == ey {
+        return first.chars[first.byte_idx(sx)..first.byte_idx(ex)].into();
+    }
+    let last = &e.buf.rows[ey];
+    let mut text: String = first.chars[first.byte_idx(sx)..].into();
+    for row in &e.buf.rows[sy + 1..ey] {
+        text.push('\n');
+        text += &row.chars;
+    }
+    text.push('\n');
+    text += &last.chars[..last.byte_idx(ex)];
+    text
+}
+
+/// Deletes the text between two positions, joining the rows at either end.
+fn editor_delete_region(e: &mut EditorConfig, (sx, sy): (usize, usize), (ex, ey): (usize, usize)) {
+    if e.buf.rows.is_empty() {
+        return;
+    }
+    let last = &e.buf.rows[ey];
+    let tail: String = last.chars[last.byte_idx(ex)..].into();
+    editor_row_truncate(e, sy, sx);
+    for _ in sy..ey {
+        editor_del_row(e, sy + 1);
+    }
+    if !tail.is_empty() {
+        editor_row_append_string(e, sy, &tail);
+    }
+    e.cx = sx;
+    e.cy = sy;
+}
+
+/// Inserts possibly multi-line text at the cursor, leaving the cursor after it.
+fn editor_insert_text(e: &mut EditorConfig, text: &str) {
+    if e.cy == e.buf.rows.len() {
+        editor_insert_row(e, e.cy, "");
+    }
+    let row = &e.buf.rows[e.cy];
+    let tail: String = row.chars[row.byte_idx(e.cx)..].into();
+    if !tail.is_empty() {
+        editor_row_truncate(e, e.cy, e.cx);
+    }
+
+    let mut lines = text.split('\n');
+    let first = lines.next().unwrap_or("");
+    if !first.is_empty() {
+        editor_row_append_string(e, e.cy, first);
+    }
+    e.cx += first.chars().count();
+    for line in lines {
+        e.cy += 1;
+        editor_insert_row(e, e.cy, line);
+        e.cx = line.chars().count();
+    }
+
+    if !tail.is_empty() {
+        editor_row_append_string(e, e.cy, &tail);
+    }
+}
+
+/*** kill ring ***/
+
+fn editor_kill_ring_add(e: &mut EditorConfig, text: String) {
+    e.kill_ring.push(text);
+    if e.kill_ring.len() > BYOTE_KILL_RING_SIZE {
+        e.kill_ring.remove(0);
+    }
+}
+
+/// Adds killed text to the ring, or to its newest entry when the previous command was a kill too.
+fn editor_kill_push(e: &mut EditorConfig, text: String, last_command: &LastCommand) {
+    match (last_command, e.kill_ring.last_mut()) {
+        (LastCommand::Kill, Some(last)) => *last += &text,
+        _ => editor_kill_ring_add(e, text),
+    }
+    e.last_command = LastCommand::Kill;
+    let text = e.kill_ring.last().cloned().unwrap_or_default();
+    editor_clipboard_export(e, &text);
+}
+
+fn editor_kill_line(e: &mut EditorConfig, last_command: &LastCommand) {
+    if e.cy >= e.buf.rows.len() {
+        return;
+    }
+    let killed = editor_row_kill(e, e.cy, e.cx);
+    // Nothing to kill at the end of the last row
+    if killed.is_empty() {
+        return;
+    }
+    editor_kill_push(e, killed, last_command);
+}
+
+fn editor_copy(e: &mut EditorConfig, cut: bool, last_command: &LastCommand) {
+    let (start, end) = match editor_selection(e) {
+        Some(region) => region,
+        None => {
+            editor_set_status_message(e, "No selection, set the mark with Ctrl-Space");
+            return;
+        }
+    };
+    let text = editor_region_text(e, start, end);
+    if cut {
+        editor_delete_region(e, start, end);
+        editor_kill_push(e, text, last_command);
+    } else {
+        editor_kill_push(e, text, &LastCommand::Other);
+    }
+    e.buf.mark = None;
+}
+
+/// The text to paste: what was last copied, here or in another program.
+fn editor_kill_ring_latest(e: &mut EditorConfig, ask_terminal: bool) -> Option<String> {
+    // Something copied in another program comes first, the older kills stay reachable with yank-pop
+    if let Some(text) = editor_clipboard_import(e, ask_terminal) {
+        if !text.is_empty() && e.kill_ring.last() != Some(&text) {
+            editor_kill_ring_add(e, text);
+        }
+    }
+    e.kill_ring.last().cloned()
+}
+
+/// Inserts the latest kill; `ask_terminal` looks at the terminal's clipboard first.
+fn editor_yank(e: &mut EditorConfig, ask_terminal: bool) {
+    editor_kill_ring_latest(e, ask_terminal);
+    let index = match e.kill_ring.len() {
+        0 => {
+            editor_set_status_message(e, "Kill ring is empty");
+            return;
+        }
+        len => len - 1,
+    };
+    // On the line after the last row, the text goes into a row that inserting it creates
+    let start = if e.cy == e.buf.rows.len() {
+        (0, e.cy)
+    } else {
+        editor_clamp_pos(e, (e.cx, e.cy))
+    };
+    let text = e.kill_ring[index].clone();
+    editor_insert_text(e, &text);
+    e.last_command = LastCommand::Yank { start, index };
+}
+
+/// Replaces the text just yanked with the next older entry of the kill ring.
+fn editor_yank_pop(e: &mut EditorConfig, last_command: &LastCommand) {
+    let (start, index) = match *last_command {
+        LastCommand::Yank { start, index } => (start, index),
+        _ => {
+            editor_set_status_message(e, "Previous command was not a yank");
+            return;
+        }
+    };
+    let len = e.kill_ring.len();
+    let index = (index + len - 1) % len;
+    editor_delete_region(e, start, (e.cx, e.cy));
+    let text = e.kill_ring[index].clone();
+    editor_insert_text(e, &text);
+    e.last_command = LastCommand::Yank { start, index };
+    editor_set_status_message(e, &format!("Kill ring entry {}/{}", len - index, len));
+}
+
+/*** clipboard ***/
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0_u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let (mut n, mut bits) = (0_u32, 0);
+    for c in s.bytes().filter(|&c| c != b'=') {
+        let v = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        n = n << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Hands copied text to the terminal and/or the configured clipboard command.
+fn editor_clipboard_export(e: &mut EditorConfig, text: &str) {
+    let mut bytes = Vec::new();
+    encode_raw_bytes(text, &mut bytes);
+    if e.osc52 {
+        print!("\x1b]52;c;{}\x07", base64_encode(&bytes));
+        flush_stdout();
+    }
+    if let Some(command) = e.clipboard_copy_command.clone() {
+        let result = Command::new("sh")
+            .args(["-c", &command])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(&bytes)?;
+                }
+                child.wait()
+            });
+        match result {
+            Ok(status) if status.success() => (),
+            Ok(status) => editor_set_status_message(e, &format!("Clipboard command {}", status)),
+            Err(err) => editor_set_status_message(e, &format!("Clipboard command failed: {}", err)),
+        }
+    }
+}
+
+/// Asks the terminal for its clipboard; `None` if it doesn't answer. Keys typed meanwhile
+/// are kept for `editor_read_key`.
+fn osc52_query(e: &mut EditorConfig) -> Option<Vec<u8>> {
+    const START: &[u8] = b"\x1b]52;";
+    print!("\x1b]52;c;?\x07");
+    flush_stdout();
+    let mut input = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    // Each empty read is a 100ms timeout
+    let mut timeouts = 0;
+    let reply = loop {
+        if timeouts == 5 {
+            break None;
+        }
+        match std::io::stdin().read(&mut buffer) {
+            Ok(0) => timeouts += 1,
+            Ok(n) => input.extend_from_slice(&buffer[..n]),
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(_) => break None,
+        }
+        let start = match input.windows(START.len()).position(|w| w == START) {
+            Some(start) => start,
+            None => continue,
+        };
+        // Terminated by BEL or ST
+        let end = (start + START.len()..input.len()).find_map(|i| match input[i] {
+            b'\x07' => Some(i + 1),
+            b'\x1b' if input.get(i + 1) == Some(&b'\\') => Some(i + 2),
+            _ => None,
+        });
+        if let Some(end) = end {
+            break Some(input.drain(start..end).collect::<Vec<u8>>());
+        }
+    };
+    e.pending_input.extend(input);
+
+    let payload = reply?;
+    let payload = &payload[START.len()..];
+    let start = payload.iter().position(|&b| b == b';')? + 1;
+    let end = payload.len() - if payload.ends_with(b"\x07") { 1 } else { 2 };
+    base64_decode(std::str::from_utf8(&payload[start.min(end)..end]).ok()?)
+}
+
+/// Text on the system clipboard, from the clipboard command, or with `ask_terminal` from the
+/// terminal. Asking the terminal waits for its answer, so it's only done on request.
+fn editor_clipboard_import(e: &mut EditorConfig, ask_terminal: bool) -> Option<String> {
+    let mut bytes = None;
+    if ask_terminal && e.osc52 {
+        bytes = osc52_query(e);
+        if bytes.is_none() {
+            editor_set_status_message(e, "The terminal didn't send its clipboard");
+        }
+    }
+    if bytes.is_none() {
+        if let Some(command) = &e.clipboard_paste_command {
+            bytes = Command::new("sh")
+                .args(["-c", command])
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| output.stdout);
+        }
+    }
+    Some(decode_raw_bytes(&bytes?).replace("\r\n", "\n"))
+}
+
+/*** undo ***/
+
+fn editor_undo_record(e: &mut EditorConfig, op: EditOp) {
+    if e.buf.undo.recording {
+        e.buf.undo.pending.push(op);
+    }
+}
+
+/// Turns the ops recorded since the last call into an undo step. Consecutive typing
+/// is merged into a single step until some other command breaks the run.
+fn editor_undo_commit(e: &mut EditorConfig, cursor_before: (usize, usize), typing: bool) {
+    let u = &mut e.buf.undo;
+    if u.pending.is_empty() {
+        u.merge_typing = u.merge_typing && typing;
+        return;
+    }
+
+    let ops: Vec<EditOp> = u.pending.drain(..).collect();
+    let id = u.next_id;
+    u.next_id += 1;
+    u.redo_stack.clear();
+
+    match u.undo_stack.last_mut() {
+        Some(last) if typing && u.merge_typing && last.cursor_after == cursor_before => {
+            last.id = id;
+            last.ops.extend(ops);
+            last.cursor_after = (e.cx, e.cy);
+        }
+        _ => u.undo_stack.push(UndoStep {
+            id,
+            ops,
+            cursor_before,
+            cursor_after: (e.cx, e.cy),
+        }),
+    }
+    u.merge_typing = typing;
+}
+
+fn editor_apply_op(e: &mut EditorConfig, op: &EditOp) {
+    match op {
+        EditOp::InsertChar { row, at, c } => editor_row_insert_char(e, *row, *at, *c),
+        EditOp::DelChar { row, at, .. } => editor_row_del_char(e, *row, *at),
+        EditOp::InsertRow { at, s } => editor_insert_row(e, *at, s),
+        EditOp::DelRow { at, .. } => editor_del_row(e, *at),
+        EditOp::AppendString { row, s, .. } => editor_row_append_string(e, *row, s),
+        EditOp::Truncate { row, at, .. } => editor_row_truncate(e, *row, *at),
+        EditOp::SetLineEnding { to, .. } => editor_set_line_ending(e, *to),
+    }
+}
+
+fn editor_undo_update_dirty(e: &mut EditorConfig) {
+    e.buf.dirty = e.buf.undo.saved_id != e.buf.undo.current_id();
+}
+
+fn editor_undo_mark_saved(e: &mut EditorConfig) {
+    e.buf.undo.saved_id = e.buf.undo.current_id();
+}
+
+fn editor_undo(e: &mut EditorConfig) {
+    match e.buf.undo.undo_stack.pop() {
+        None => editor_set_status_message(e, "Nothing to undo"),
+        Some(step) => {
+            e.buf.undo.recording = false;
+            for op in step.ops.iter().rev() {
+                editor_apply_op(e, &op.inverse());
+            }
+            e.buf.undo.recording = true;
+            e.cx = step.cursor_before.0;
+            e.cy = step.cursor_before.1;
+            e.buf.undo.redo_stack.push(step);
+            e.buf.undo.merge_typing = false;
+            editor_undo_update_dirty(e);
+        }
+    }
+}
+
+fn editor_redo(e: &mut EditorConfig) {
+    match e.buf.undo.redo_stack.pop() {
+        None => editor_set_status_message(e, "Nothing to redo"),
+        Some(step) => {
+            e.buf.undo.recording = false;
+            for op in step.ops.iter() {
+                editor_apply_op(e, op);
+            }
+            e.buf.undo.recording = true;
+            e.cx = step.cursor_after.0;
+            e.cy = step.cursor_after.1;
+            e.buf.undo.undo_stack.push(step);
+            e.buf.undo.merge_typing = false;
+            editor_undo_update_dirty(e);
+        }
+    }
+}
+
+/*** file i/o ***/
+
+fn raw_byte_to_char(b: u8) -> char {
+    std::char::from_u32(RAW_BYTE_BASE + u32::from(b)).unwrap()
+}
+
+fn char_to_raw_byte(c: char) -> Option<u8> {
+    match c as u32 {
+        x if x >= RAW_BYTE_BASE + 0x80 => Some((x - RAW_BYTE_BASE) as u8),
+        _ => None,
+    }
+}
+
+/// Decodes `bytes` as UTF-8, storing anything that isn't valid UTF-8 as raw byte characters.
+/// Genuine characters from the raw byte range are stored byte by byte too, so that
+/// `encode_raw_bytes` always gives back the original bytes.
+fn decode_raw_bytes(mut bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    let push_valid = |s: &mut String, valid: &str| {
+        for c in valid.chars() {
+            if char_to_raw_byte(c).is_some() {
+                let mut buf = [0_u8; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    s.push(raw_byte_to_char(b));
+                }
+            } else {
+                s.push(c);
+            }
+        }
+    };
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                push_valid(&mut s, valid);
+                return s;
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                push_valid(&mut s, std::str::from_utf8(valid).unwrap());
+                let invalid_len = err.error_len().unwrap_or(rest.len());
+                for b in &rest[..invalid_len] {
+                    s.push(raw_byte_to_char(*b));
+                }
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
+}
+
+fn encode_raw_bytes(s: &str, out: &mut Vec<u8>) {
+    for c in s.chars() {
+        match char_to_raw_byte(c) {
+            Some(b) => out.push(b),
+            None => {
+                let mut buf = [0_u8; 4];
+                out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+}
+
+fn editor_rows_to_bytes(e: &EditorConfig) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (i, row) in e.buf.rows.iter().enumerate() {
+        if i > 0 {
+            buf.extend_from_slice(e.buf.line_ending.as_bytes());
+        }
+        encode_raw_bytes(&row.chars, &mut buf);
+    }
+    if e.buf.final_newline && !e.buf.rows.is_empty() {
+        buf.extend_from_slice(e.buf.line_ending.as_bytes());
+    }
+    buf
+}
+
+/// Replaces the contents of the buffer with `bytes`, without recording undo history.
+fn editor_load_bytes(e: &mut EditorConfig, bytes: &[u8]) {
+    e.buf.rows.clear();
+    let mut lines: Vec<&[u8]> = bytes.split(|b| *b == b'\n').collect();
+    e.buf.final_newline = lines.last() == Some(&&b""[..]);
+    if e.buf.final_newline {
+        lines.pop();
+    }
+
+    // Go with whichever line ending most lines use. Lines that don't match it keep their
+    // '\r' (or lack of it) in the row in LF mode, and get normalized in CRLF mode.
+    let line_count = lines.len() - if e.buf.final_newline { 0 } else { 1 };
+    let crlf_count = lines[..line_count]
+        .iter()
+        .filter(|l| l.ends_with(b"\r"))
+        .count();
+    e.buf.line_ending = if crlf_count * 2 > line_count {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Lf
+    };
+
+    e.buf.undo.recording = false;
+    for (i, line) in lines.into_iter().enumerate() {
+        let line = match e.buf.line_ending {
+            LineEnding::CrLf if i < line_count => line.strip_suffix(b"\r").unwrap_or(line),
+            _ => line,
+        };
+        editor_insert_row(e, e.buf.rows.len(), &decode_raw_bytes(line));
+    }
+    e.buf.undo = UndoState::new();
+    e.buf.dirty = false;
+}
+
+/// Loads `filename` into the current buffer. A file that doesn't exist yet gives an empty
+/// buffer that will create it on save.
+fn editor_open(e: &mut EditorConfig, filename: &str) -> std::io::Result<()> {
+    let bytes = match std::fs::read(filename) {
+        Ok(bytes) => bytes,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+            editor_set_status_message(e, &format!("New file: {}", filename));
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    e.buf.filename = Some(filename.into());
+    editor_select_syntax_highlight(e);
+    editor_load_bytes(e, &bytes);
+    editor_disk_stamp_update(e);
+    editor_swap_check(e);
+    Ok(())
+}
+
+fn editor_disk_stamp_update(e: &mut EditorConfig) {
+    e.buf.disk_stamp = e.buf.filename.as_ref().and_then(|f| FileStamp::of(f));
+    e.buf.disk_change_reported = false;
+}
+
+/// Whether the file was modified (or deleted) on disk since we last read or wrote it.
+fn editor_disk_changed(e: &EditorConfig) -> bool {
+    match &e.buf.filename {
+        Some(filename) => FileStamp::of(filename) != e.buf.disk_stamp,
+        None => false,
+    }
+}
+
+fn editor_reload(e: &mut EditorConfig) {
+    let filename = match &e.buf.filename {
+        Some(filename) => filename.clone(),
+        None => {
+            editor_set_status_message(e, "Nothing to reload, buffer has no file");
+            return;
+        }
+    };
+    if e.buf.dirty
+        && editor_prompt_choice(e, "Discard unsaved changes and reload? (y/n) ", "yn") != Some('y')
+    {
+        editor_set_status_message(e, "Reload aborted");
+        return;
+    }
+
+    match std::fs::read(&filename) {
+        Ok(bytes) => {
+            editor_load_bytes(e, &bytes);
+            editor_disk_stamp_update(e);
+            editor_swap_remove(&mut e.buf);
+            e.cy = e.cy.min(e.buf.rows.len());
+            e.cx = e
+                .buf
+                .rows
+                .get(e.cy)
+                .map(|r| r.snap_to_boundary(e.cx))
+                .unwrap_or(0);
+            editor_set_status_message(e, &format!("Reloaded {}", filename));
+        }
+        Err(err) => editor_set_status_message(e, &format!("Can't reload! I/O error: {}", err)),
+    }
+}
+
+/// Writes `data` to `filename` without ever leaving a half-written file behind: the data goes
+/// to a temporary file in the same directory, which is synced and then renamed over the target.
+/// Symlinks are followed, and the permissions and ownership of an existing file are kept,
+/// unless a `private` file is asked for: that is only ever readable by its owner.
+/// With `backup`, the previous contents stay around as `filename~`.
+/// When the directory isn't writable, an existing file is overwritten in place instead. Returns
+/// a note for the user when the file was written but not quite as asked.
+fn write_file_atomically(
+    filename: &str,
+    data: &[u8],
+    backup: bool,
+    private: bool,
+) -> std::io::Result<Option<String>> {
+    let mut target = PathBuf::from(filename);
+    for _ in 0..40 {
+        match std::fs::read_link(&target) {
+            Ok(link) => target = target.parent().map(|p| p.join(&link)).unwrap_or(link),
+            Err(_) => break,
+        }
+    }
+
+    let dir = match target.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = target
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "not a file name"))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(".byote-{}.tmp", std::process::id()));
+    let tmp = dir.join(tmp_name);
+    let original = std::fs::metadata(&target).ok();
+
+    let mut note = None;
+    let result = (|| -> std::io::Result<()> {
+        let mut file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(if private { 0o600 } else { 0o666 })
+            .open(&tmp)
+        {
+            Ok(file) => file,
+            // Not atomic, but better than not saving at all. A backup would need the directory
+            // to be writable too.
+            Err(err) if err.kind() == ErrorKind::PermissionDenied && original.is_some() => {
+                let mut file = OpenOptions::new().write(true).open(&target)?;
+                file.write_all(data)?;
+                file.set_len(data.len() as u64)?;
+                file.sync_all()?;
+                let how = if backup { ", no backup" } else { "" };
+                note = Some(format!("in place{}: directory not writable", how));
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        file.write_all(data)?;
+        if let Some(meta) = original.as_ref().filter(|_| !private) {
+            file.set_permissions(meta.permissions())?;
+            // Only root can give a file away, so keeping the owner is best effort
+            let _ = std::os::unix::fs::fchown(&file, Some(meta.uid()), Some(meta.gid()));
+        }
+        file.sync_all()?;
+
+        // The new contents are safe, so a failed backup doesn't stop the save
+        if backup && original.is_some() {
+            if let Err(err) = write_backup(&target) {
+                note = Some(format!("no backup: {}", err));
+            }
+        }
+
+        std::fs::rename(&tmp, &target)
+    })();
+
+    match result {
+        Ok(()) => {
+            // Make the rename itself durable; not every file system supports this
+            if let Ok(d) = std::fs::File::open(&dir) {
+                let _ = d.sync_all();
+            }
+            Ok(note)
+        }
+        Err(err) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(err)
+        }
+    }
+}
+
+/// Keeps the current contents of `target` as `target~`.
+fn write_backup(target: &Path) -> std::io::Result<()> {
+    let mut backup_name = target.to_path_buf().into_os_string();
+    backup_name.push("~");
+    let _ = std::fs::remove_file(&backup_name);
+    if std::fs::hard_link(target, &backup_name).is_err() {
+        std::fs::copy(target, &backup_name)?;
+    }
+    Ok(())
+}
+
+fn editor_save(e: &mut EditorConfig) {
+    if e.buf.filename.is_none() {
+        e.buf.filename = editor_prompt(e, "Save as (ESC to cancel): ", PromptKind::File, None);
+        if e.buf.filename.is_none() {
+            editor_set_status_message(e, "Save aborted!");
+            return;
+        }
+        editor_select_syntax_highlight(e);
+    }
+
+    if editor_disk_changed(e)
+        && editor_prompt_choice(
+            e,
+            "File changed on disk since it was read. Overwrite it? (y/n) ",
+            "yn",
+        ) != Some('y')
+    {
+        editor_set_status_message(e, "Save aborted!");
+        return;
+    }
+
+    if let Some(filename) = &e.buf.filename {
+        let buf = editor_rows_to_bytes(e);
+        let msg = &write_file_atomically(filename, &buf, e.backup_files, false)
+            .map(|note| {
+                e.buf.dirty = false;
+                match note {
+                    Some(note) => format!("{} bytes written to disk ({})", buf.len(), note),
+                    None => format!("{} bytes written to disk", buf.len()),
+                }
+            })
+            .unwrap_or_else(|e| format!("Can't save! I/O error: {}", e));
+        if !e.buf.dirty {
+            editor_undo_mark_saved(e);
+            editor_swap_remove(&mut e.buf);
+            editor_disk_stamp_update(e);
+        }
+        editor_set_status_message(e, msg);
+    }
+}
+
+/*** swap ***/
+
+const SWAP_MAGIC: &str = "BYOTE-SWAP 1";
+
+fn editor_swap_path(filename: &str) -> PathBuf {
+    let path = PathBuf::from(filename);
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".byote-swp");
+    path.with_file_name(name)
+}
+
+/// A swap file is a small text header, an empty line, then the buffer as it would be saved.
+fn editor_swap_write(e: &mut EditorConfig) {
+    let filename = match &e.buf.filename {
+        Some(filename) => filename.clone(),
+        None => return,
+    };
+    let mut data = format!(
+        "{}\npid {}\nline-ending {}\nfinal-newline {}\ncursor {} {}\n\n",
+        SWAP_MAGIC,
+        std::process::id(),
+        e.buf.line_ending.name(),
+        e.buf.final_newline as u8,
+        e.cx,
+        e.cy
+    )
+    .into_bytes();
+    data.extend(editor_rows_to_bytes(e));
+
+    let path = editor_swap_path(&filename);
+    // Unsaved edits of a private file mustn't become readable by others
+    match write_file_atomically(&path.to_string_lossy(), &data, false, true) {
+        Ok(_) => {
+            e.buf.swap_id = e.buf.undo.current_id();
+            e.buf.swap_failures = 0;
+        }
+        Err(err) => {
+            if e.buf.swap_failures == 0 {
+                editor_set_status_message(e, &format!("Can't write swap file: {}", err));
+            }
+            e.buf.swap_failures += 1;
+        }
+    }
+    e.buf.swap_time = Instant::now();
+}
+
+fn editor_swap_remove(b: &mut Buffer) {
+    if let Some(filename) = &b.filename {
+        let _ = std::fs::remove_file(editor_swap_path(filename));
+    }
+    b.swap_id = b.undo.current_id();
+}
+
+/// Called while waiting for input; between commands it keeps the swap file in sync with the
+/// buffer, and notices when the file is changed by someone else. Prompts and longer bindings
+/// own the status bar, so nothing is done while they wait.
+fn editor_idle(e: &mut EditorConfig) {
+    if !e.awaiting_command {
+        return;
+    }
+    if !e.buf.disk_change_reported && editor_disk_changed(e) {
+        e.buf.disk_change_reported = true;
+        editor_set_status_message(e, "WARNING!!! File changed on disk. Alt-R = reload");
+        editor_refresh_screen(e);
+    }
+
+    // Each failed write doubles the wait, up to a minute or so
+    let interval = BYOTE_SWAP_INTERVAL_SECS << e.buf.swap_failures.min(5);
+    if e.buf.swap_id == e.buf.undo.current_id()
+        || e.buf.swap_time.elapsed() < Duration::from_secs(interval)
+    {
+        return;
+    }
+    if e.buf.dirty {
+        editor_swap_write(e);
+    } else {
+        editor_swap_remove(&mut e.buf);
+    }
+}
+
+struct SwapFile {
+    pid: i32,
+    line_ending: LineEnding,
+    final_newline: bool,
+    cursor: (usize, usize),
+    contents: Vec<u8>,
+}
+
+fn parse_swap_file(data: &[u8]) -> Option<SwapFile> {
+    let header_end = data.windows(2).position(|w| w == b"\n\n")?;
+    let header = std::str::from_utf8(&data[..header_end]).ok()?;
+    let mut lines = header.lines();
+    if lines.next()? != SWAP_MAGIC {
+        return None;
+    }
+
+    let mut swap = SwapFile {
+        pid: 0,
+        line_ending: LineEnding::Lf,
+        final_newline: true,
+        cursor: (0, 0),
+        contents: data[header_end + 2..].to_vec(),
+    };
+    for line in lines {
+        let mut words = line.split(' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some("pid"), Some(pid), None) => swap.pid = pid.parse().ok()?,
+            (Some("line-ending"), Some("CRLF"), None) => swap.line_ending = LineEnding::CrLf,
+            (Some("final-newline"), Some(v), None) => swap.final_newline = v == "1",
+            (Some("cursor"), Some(cx), Some(cy)) => {
+                swap.cursor = (cx.parse().ok()?, cy.parse().ok()?)
+            }
+            _ => (),
+        }
+    }
+    Some(swap)
+}
+
+// Beyond this many cells of the table, the changed middle is shown as a single hunk
+const DIFF_MAX_CELLS: usize = 4_000_000;
+
+/// The runs of lines that differ between `old` and `new`, as (first line in `old`, lines
+/// removed, lines added). After trimming the common start and end, the lines kept are a
+/// longest common subsequence of what's left.
+fn diff_lines<'a>(
+    old: &'a [String],
+    new: &'a [String],
+) -> Vec<(usize, &'a [String], &'a [String])> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+    if a.is_empty() && b.is_empty() {
+        return Vec::new();
+    }
+    if a.len() * b.len() > DIFF_MAX_CELLS {
+        return vec![(prefix, a, b)];
+    }
+
+    // lcs[i * width + j] is the length of a longest common subsequence of a[i..] and b[j..]
+    let width = b.len() + 1;
+    let mut lcs = vec![0_u32; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i * width + j] = if a[i] == b[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    // Where the current hunk started
+    let (mut hi, mut hj) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            if (hi, hj) != (i, j) {
+                hunks.push((prefix + hi, &a[hi..i], &b[hj..j]));
+            }
+            i += 1;
+            j += 1;
+            hi = i;
+            hj = j;
+        } else if j == b.len()
+            || (i < a.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+        {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    if (hi, hj) != (i, j) {
+        hunks.push((prefix + hi, &a[hi..i], &b[hj..j]));
+    }
+    hunks
+}
+
+/// Describes the hunks where the swap file differs from the buffer, one message per hunk with
+/// the lines removed from the file and added by the swap file.
+fn editor_swap_diff(e: &EditorConfig, swap: &SwapFile) -> Vec<String> {
+    let swap_lines: Vec<String> = swap
+        .contents
+        .split(|b| *b == b'\n')
+        .map(|l| decode_raw_bytes(l.strip_suffix(b"\r").unwrap_or(l)))
+        .collect();
+    let swap_lines = &swap_lines[..swap_lines.len() - swap.final_newline as usize];
+    let rows: Vec<String> = e.buf.rows.iter().map(|row| row.chars.clone()).collect();
+
+    diff_lines(&rows, swap_lines)
+        .into_iter()
+        .map(|(at, removed, added)| {
+            let lines: Vec<String> = removed
+                .iter()
+                .map(|line| format!("-{}", line))
+                .chain(added.iter().map(|line| format!("+{}", line)))
+                .collect();
+            format!("line {}: {}", at + 1, lines.join(" ").replace('\t', " "))
+        })
+        .collect()
+}
+
+/// Looks for a swap file left behind for the file just opened, and asks what to do with it.
+fn editor_swap_check(e: &mut EditorConfig) {
+    let path = match &e.buf.filename {
+        Some(filename) => editor_swap_path(filename),
+        None => return,
+    };
+    let swap = match std::fs::read(&path).ok().and_then(|d| parse_swap_file(&d)) {
+        Some(swap) => swap,
+        None => return,
+    };
+
+    let running =
+        swap.pid as u32 != std::process::id() && kill(Pid::from_raw(swap.pid), None).is_ok();
+    let prompt = format!(
+        "Found swap file{}. (r)ecover, (d)iff or (x) discard it, ESC to ignore? ",
+        if running {
+            format!(" of running process {}", swap.pid)
+        } else {
+            String::new()
+        }
+    );
+
+    loop {
+        match editor_prompt_choice(e, &prompt, "rdx") {
+            Some('r') => {
+                editor_load_bytes(e, &swap.contents);
+                e.buf.line_ending = swap.line_ending;
+                e.buf.final_newline = swap.final_newline;
+                e.cy = swap.cursor.1.min(e.buf.rows.len());
+                e.cx = e
+                    .buf
+                    .rows
+                    .get(e.cy)
+                    .map(|r| r.snap_to_boundary(swap.cursor.0))
+                    .unwrap_or(0);
+                // The buffer now differs from the file on disk, make sure undo knows
+                e.buf.undo.saved_id = u64::MAX;
+                e.buf.dirty = true;
+                editor_set_status_message(e, "Recovered from swap file");
+                return;
+            }
+            Some('d') => {
+                let hunks = editor_swap_diff(e, &swap);
+                if hunks.is_empty() {
+                    editor_set_status_message(e, "Swap file matches the file on disk");
+                    editor_refresh_screen(e);
+                    editor_read_key(e);
+                }
+                // Any key shows the next hunk, ESC goes back to the question
+                for (i, hunk) in hunks.iter().enumerate() {
+                    let msg = format!("Swap diff {}/{}, {}", i + 1, hunks.len(), hunk);
+                    editor_set_status_message(e, &msg);
+                    editor_refresh_screen(e);
+                    if editor_read_key(e) == EditorKey::Escape {
+                        break;
+                    }
+                }
+            }
+            Some('x') => {
+                let _ = std::fs::remove_file(&path);
+                editor_set_status_message(e, "Swap file discarded");
+                return;
+            }
+            Some(_) => (),
+            None => {
+                editor_set_status_message(e, "Swap file ignored, editing will replace it");
+                return;
+            }
+        }
+    }
+}
+
+/*** buffers ***/
+
+fn editor_buffer(e: &EditorConfig, idx: usize) -> &Buffer {
+    if idx == e.current {
+        &e.buf
+    } else {
+        &e.buffers[idx]
+    }
+}
+
+fn editor_save_view(e: &mut EditorConfig) {
+    e.buf.cx = e.cx;
+    e.buf.cy = e.cy;
+    e.buf.rowoff = e.rowoff;
+    e.buf.coloff = e.coloff;
+}
+
+fn editor_restore_view(e: &mut EditorConfig) {
+    e.cx = e.buf.cx;
+    e.cy = e.buf.cy;
+    e.rowoff = e.buf.rowoff;
+    e.coloff = e.buf.coloff;
+}
+
+fn editor_switch_buffer(e: &mut EditorConfig, idx: usize) {
+    if idx == e.current || idx >= e.buffers.len() {
+        return;
+    }
+    // Only the current buffer gets its swap file written while idle
+    if e.buf.dirty && e.buf.swap_id != e.buf.undo.current_id() {
+        editor_swap_write(e);
+    }
+    editor_save_view(e);
+    std::mem::swap(&mut e.buf, &mut e.buffers[e.current]);
+    e.current = idx;
+    std::mem::swap(&mut e.buf, &mut e.buffers[e.current]);
+    editor_restore_view(e);
+    e.windows[e.current_window].buffer = idx;
+}
+
+fn editor_cycle_buffer(e: &mut EditorConfig, delta: isize) {
+    let n = e.buffers.len() as isize;
+    let idx = (e.current as isize + delta).rem_euclid(n) as usize;
+    editor_switch_buffer(e, idx);
+}
+
+/// Opens `filename` in a buffer of its own, or switches to it if it's already open.
+fn editor_open_buffer(e: &mut EditorConfig, filename: &str) {
+    if let Some(idx) =
+        (0..e.buffers.len()).find(|&i| editor_buffer(e, i).filename.as_deref() == Some(filename))
+    {
+        editor_switch_buffer(e, idx);
+        return;
+    }
+
+    // Reuse the empty buffer we start with, as long as nothing was typed into it
+    let previous = e.current;
+    let reuse = e.buf.filename.is_none() && !e.buf.dirty && e.buf.rows.is_empty();
+    if !reuse {
+        e.buffers.push(Buffer::new());
+        editor_switch_buffer(e, e.buffers.len() - 1);
+    }
+    if let Err(err) = editor_open(e, filename) {
+        editor_set_status_message(e, &format!("Can't open {}: {}", filename, err));
+        // Go back to where we were; the new buffer is still empty and shown nowhere else
+        if !reuse {
+            editor_switch_buffer(e, previous);
+            e.buffers.pop();
+        }
+    }
+}
+
+fn editor_close_buffer(e: &mut EditorConfig) {
+    if e.buf.dirty
+        && editor_prompt_choice(
+            e,
+            "Buffer has unsaved changes. Close it anyway? (y/n) ",
+            "yn",
+        ) != Some('y')
+    {
+        editor_set_status_message(e, "Close aborted");
+        return;
+    }
+
+    editor_swap_remove(&mut e.buf);
+    let closed = e.current;
+    e.buffers.remove(closed);
+    if e.buffers.is_empty() {
+        e.buffers.push(Buffer::new());
+    }
+    e.current = e.current.min(e.buffers.len() - 1);
+    e.buf = std::mem::replace(&mut e.buffers[e.current], Buffer::new());
+    editor_restore_view(e);
+
+    // Windows that showed the closed buffer show the one that replaced it instead
+    for w in e.windows.iter_mut() {
+        if w.buffer == closed {
+            w.buffer = e.current;
+        } else if w.buffer > closed {
+            w.buffer -= 1;
+        }
+    }
+}
+
+fn editor_list_buffers(e: &mut EditorConfig) {
+    let list: Vec<String> = (0..e.buffers.len())
+        .map(|i| {
+            let b = editor_buffer(e, i);
+            format!("{}:{}{}", i + 1, b.name(), if b.dirty { "*" } else { "" })
+        })
+        .collect();
+    let prompt = format!("{} | Switch to: ", list.join(" "));
+    let choice = match editor_prompt(e, &prompt, PromptKind::Buffer, None) {
+        Some(choice) => choice,
+        None => return,
+    };
+
+    let idx = match choice.trim().parse::<usize>() {
+        Ok(n) if n >= 1 && n <= e.buffers.len() => Some(n - 1),
+        _ => (0..e.buffers.len()).find(|&i| editor_buffer(e, i).name().contains(choice.trim())),
+    };
+    match idx {
+        Some(idx) => editor_switch_buffer(e, idx),
+        None => editor_set_status_message(e, &format!("No buffer matches {}", choice)),
+    }
+}
+
+/*** windows ***/
+
+/// Works out where each window in `layout` goes within the given area, including its status bar.
+fn layout_rects(
+    layout: &Layout,
+    (top, left, height, width): Rect,
+    rects: &mut Vec<(usize, Rect)>,
+    separators: &mut Vec<(usize, usize, usize)>,
+) {
+    match layout {
+        Layout::Window(w) => rects.push((*w, (top, left, height, width))),
+        Layout::Split {
+            vertical: true,
+            first_size,
+            first,
+            second,
+        } => {
+            let first_width = (*first_size).min(width.saturating_sub(2)).max(1);
+            layout_rects(first, (top, left, height, first_width), rects, separators);
+            separators.push((top, left + first_width, height));
+            let second_width = width.saturating_sub(first_width + 1);
+            let area = (top, left + first_width + 1, height, second_width);
+            layout_rects(second, area, rects, separators);
+        }
+        Layout::Split {
+            vertical: false,
+            first_size,
+            first,
+            second,
+        } => {
+            let first_height = (*first_size).min(height.saturating_sub(2)).max(2);
+            layout_rects(first, (top, left, first_height, width), rects, separators);
+            let second_height = height.saturating_sub(first_height);
+            let area = (top + first_height, left, second_height, width);
+            layout_rects(second, area, rects, separators);
+        }
+    }
+}
+
+/// Replaces the `Layout::Window(target)` leaf with a split of it and `new`.
+fn layout_split(layout: &mut Layout, target: usize, new: usize, vertical: bool, size: usize) {
+    match layout {
+        Layout::Window(w) if *w == target => {
+            *layout = Layout::Split {
+                vertical,
+                first_size: size,
+                first: Box::new(Layout::Window(target)),
+                second: Box::new(Layout::Window(new)),
+            }
+        }
+        Layout::Window(_) => (),
+        Layout::Split { first, second, .. } => {
+            layout_split(first, target, new, vertical, size);
+            layout_split(second, target, new, vertical, size);
+        }
+    }
+}
+
+/// Removes window `target`, giving its space to its sibling, and renumbers the windows after it.
+fn layout_remove(layout: Layout, target: usize) -> Option<Layout> {
+    match layout {
+        Layout::Window(w) if w == target => None,
+        Layout::Window(w) if w > target => Some(Layout::Window(w - 1)),
+        Layout::Window(w) => Some(Layout::Window(w)),
+        Layout::Split {
+            vertical,
+            first_size,
+            first,
+            second,
+        } => match (
+            layout_remove(*first, target),
+            layout_remove(*second, target),
+        ) {
+            (Some(first), Some(second)) => Some(Layout::Split {
+                vertical,
+                first_size,
+                first: Box::new(first),
+                second: Box::new(second),
+            }),
+            (Some(only), None) | (None, Some(only)) => Some(only),
+            (None, None) => None,
+        },
+    }
+}
 
-    match key {
-        EditorKey::Escape | EditorKey::Return => {
-            e.find.last_match = -1;
-            e.find.direction = 1;
-            return;
+/// Grows window `target` by `delta` at the expense of its sibling, within the (height, width)
+/// the layout has. Both keep at least the size `layout_rects` gives them. Returns whether the
+/// window had a sibling.
+fn layout_resize(
+    layout: &mut Layout,
+    (height, width): (usize, usize),
+    target: usize,
+    delta: isize,
+) -> bool {
+    match layout {
+        Layout::Window(_) => false,
+        Layout::Split {
+            vertical,
+            first_size,
+            first,
+            second,
+        } => {
+            // The sizes the first one may have, and the areas both have now
+            let (min, max) = if *vertical {
+                (1, width.saturating_sub(2).max(1))
+            } else {
+                (2, height.saturating_sub(2).max(2))
+            };
+            let current = (*first_size).clamp(min, max);
+            let (first_area, second_area) = if *vertical {
+                (
+                    (height, current),
+                    (height, width.saturating_sub(current + 1)),
+                )
+            } else {
+                ((current, width), (height.saturating_sub(current), width))
+            };
+
+            let grow = match (&**first, &**second) {
+                (Layout::Window(w), _) if *w == target => delta,
+                (_, Layout::Window(w)) if *w == target => -delta,
+                _ => {
+                    return layout_resize(first, first_area, target, delta)
+                        || layout_resize(second, second_area, target, delta)
+                }
+            };
+            *first_size = (current as isize + grow).clamp(min as isize, max as isize) as usize;
+            true
         }
-        EditorKey::ArrowLeft | EditorKey::ArrowUp => e.find.direction = -1,
-        EditorKey::ArrowDown | EditorKey::ArrowRight => e.find.direction = 1,
-        _ => {
-            e.find.last_match = -1;
-            e.find.direction = 1;
+    }
+}
+
+fn editor_save_window(e: &mut EditorConfig) {
+    let w = &mut e.windows[e.current_window];
+    w.cx = e.cx;
+    w.cy = e.cy;
+    w.rx = e.rx;
+    w.rowoff = e.rowoff;
+    w.coloff = e.coloff;
+}
+
+fn editor_load_window(e: &mut EditorConfig) {
+    let w = e.windows[e.current_window];
+    e.screentop = w.top;
+    e.screenleft = w.left;
+    e.screenrows = w.height;
+    e.screencols = w.width;
+    e.cy = w.cy.min(e.buf.rows.len());
+    e.cx = e
+        .buf
+        .rows
+        .get(e.cy)
+        .map(|r| r.snap_to_boundary(w.cx))
+        .unwrap_or(0);
+    e.rx = w.rx;
+    e.rowoff = w.rowoff;
+    e.coloff = w.coloff;
+}
+
+/// Recomputes the geometry of every window from `e.layout`.
+fn editor_layout(e: &mut EditorConfig) {
+    let mut rects = Vec::new();
+    e.separators.clear();
+    let area = (0, 0, e.term_rows - 1, e.term_cols);
+    layout_rects(&e.layout, area, &mut rects, &mut e.separators);
+    for (idx, (top, left, height, width)) in rects {
+        let w = &mut e.windows[idx];
+        w.top = top;
+        w.left = left;
+        w.height = height.saturating_sub(1).max(1);
+        w.width = width.max(1);
+    }
+    let w = e.windows[e.current_window];
+    e.screentop = w.top;
+    e.screenleft = w.left;
+    e.screenrows = w.height;
+    e.screencols = w.width;
+}
+
+fn editor_switch_window(e: &mut EditorConfig, idx: usize) {
+    if idx == e.current_window {
+        return;
+    }
+    editor_save_window(e);
+    e.current_window = idx;
+    editor_switch_buffer(e, e.windows[idx].buffer);
+    editor_load_window(e);
+}
+
+/// Window indices in the order they appear on screen.
+fn editor_window_order(e: &EditorConfig) -> Vec<usize> {
+    let mut rects = Vec::new();
+    layout_rects(&e.layout, (0, 0, 0, 0), &mut rects, &mut Vec::new());
+    rects.into_iter().map(|(w, _)| w).collect()
+}
+
+/// Moves to the next (or previous) window in screen order.
+fn editor_cycle_window(e: &mut EditorConfig, delta: isize) {
+    let order = editor_window_order(e);
+    let pos = order
+        .iter()
+        .position(|w| *w == e.current_window)
+        .unwrap_or(0) as isize;
+    let next = order[(pos + delta).rem_euclid(order.len() as isize) as usize];
+    editor_switch_window(e, next);
+}
+
+fn editor_split_window(e: &mut EditorConfig, vertical: bool) {
+    // Both halves need room for at least one line (plus status bar) or column
+    let (total, min) = if vertical {
+        (e.screencols, 3)
+    } else {
+        (e.screenrows + 1, 4)
+    };
+    if total < min {
+        editor_set_status_message(e, "Window too small to split");
+        return;
+    }
+
+    editor_save_window(e);
+    let new = e.windows.len();
+    e.windows.push(e.windows[e.current_window]);
+    let size = if vertical {
+        total / 2
+    } else {
+        total.div_ceil(2)
+    };
+    layout_split(&mut e.layout, e.current_window, new, vertical, size);
+    editor_layout(e);
+}
+
+fn editor_close_window(e: &mut EditorConfig) {
+    if e.windows.len() == 1 {
+        editor_set_status_message(e, "Can't close the only window");
+        return;
+    }
+
+    let closed = e.current_window;
+    let order = editor_window_order(e);
+    let pos = order.iter().position(|w| *w == closed).unwrap_or(0);
+    let next = if pos > 0 {
+        order[pos - 1]
+    } else {
+        order[pos + 1]
+    };
+
+    let layout = std::mem::replace(&mut e.layout, Layout::Window(0));
+    e.layout = layout_remove(layout, closed).unwrap();
+    e.windows.remove(closed);
+    e.current_window = if next > closed { next - 1 } else { next };
+    editor_layout(e);
+    editor_switch_buffer(e, e.windows[e.current_window].buffer);
+    editor_load_window(e);
+}
+
+fn editor_resize_window(e: &mut EditorConfig, delta: isize) {
+    let area = (e.term_rows - 1, e.term_cols);
+    if !layout_resize(&mut e.layout, area, e.current_window, delta) {
+        editor_set_status_message(e, "Nothing to resize, there is only one window");
+        return;
+    }
+    editor_layout(e);
+}
+
+/*** find ***/
+
+/// Turns `\n`, `\t` and `\\` in a typed query or replacement into the characters they stand for.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('\\') => out.push('\\'),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Compiles the query according to the search options; plain queries are escaped.
+fn editor_find_regex(e: &EditorConfig, query: &str) -> std::result::Result<Regex, regex::Error> {
+    let pattern = if e.find.regex {
+        query.to_string()
+    } else {
+        regex::escape(&unescape(query))
+    };
+    let ignore_case = match e.find.case {
+        CaseMode::Sensitive => false,
+        CaseMode::Insensitive => true,
+        // Escapes like `\W` or `\N` don't count as uppercase
+        CaseMode::Smart => !query
+            .chars()
+            .scan(' ', |prev, c| {
+                let escaped = *prev == '\\';
+                *prev = if escaped { ' ' } else { c };
+                Some(!escaped && c.is_uppercase())
+            })
+            .any(|upper| upper),
+    };
+    // Rows are searched joined with newlines, `^` and `$` still match at their ends
+    RegexBuilder::new(&pattern)
+        .case_insensitive(ignore_case)
+        .multi_line(true)
+        .build()
+}
+
+/// The first match starting at or after byte `from`, as a byte range. Whole-word matches must not
+/// have a word character right before or after them.
+fn find_match(regex: &Regex, whole_word: bool, text: &str, from: usize) -> Option<(usize, usize)> {
+    let mut from = from;
+    while from <= text.len() {
+        let m = regex.find_at(text, from)?;
+        let (start, end) = (m.start(), m.end());
+        if !whole_word
+            || (text[..start].chars().next_back().is_none_or(is_separator)
+                && text[end..].chars().next().is_none_or(is_separator))
+        {
+            return Some((start, end));
         }
+        from = start + text[start..].chars().next().map_or(1, char::len_utf8);
     }
+    None
+}
 
-    if e.find.last_match == -1 {
-        e.find.direction = 1;
+fn find_all_matches(regex: &Regex, whole_word: bool, text: &str) -> Vec<(usize, usize)> {
+    let mut matches = Vec::new();
+    let mut from = 0;
+    while let Some((start, end)) = find_match(regex, whole_word, text, from) {
+        matches.push((start, end));
+        // An empty match would be found again
+        from = end.max(start + text[start..].chars().next().map_or(1, char::len_utf8));
     }
+    matches
+}
 
-    let mut current = e.find.last_match;
-    for _y in 0..e.rows.len() {
-        current += e.find.direction as isize;
-        if current == -1 {
-            current = (e.rows.len() - 1) as isize;
-        } else if current == e.rows.len() as isize {
-            current = 0;
+/// The text of `rows` joined with newlines, and where each row starts in it.
+fn rows_text(rows: &[ERow]) -> (String, Vec<usize>) {
+    let mut text = String::new();
+    let mut starts = Vec::with_capacity(rows.len());
+    for (y, row) in rows.iter().enumerate() {
+        if y > 0 {
+            text.push('\n');
         }
-        let row = &e.rows[current as usize];
-        match row.render.find(&query) {
-            None => (),
-            Some(rx) => {
-                e.find.last_match = current;
-                e.cy = current as usize;
-                e.cx = editor_row_rx_to_cx(row, rx);
-                e.rowoff = e.rows.len();
+        starts.push(text.len());
+        text += &row.chars;
+    }
+    (text, starts)
+}
 
-                e.find.saved_hl_line = current as usize;
-                e.find.saved_hl = Some(e.rows[e.cy].hl.clone());
-                e.rows[e.cy]
-                    .hl
-                    .splice(rx..rx + query.len(), vec![Highlight::Match; query.len()]);
-                break;
+/// Converts an offset into `rows_text` to a (row, byte) position.
+fn text_pos(starts: &[usize], offset: usize) -> (usize, usize) {
+    let y = starts.partition_point(|&start| start <= offset) - 1;
+    (y, offset - starts[y])
+}
+
+/// Moves a (row, byte) position `n` bytes forward in the text of `rows`, counting a byte for
+/// each line break.
+fn text_advance(rows: &[ERow], (mut y, mut b): (usize, usize), mut n: usize) -> (usize, usize) {
+    while b + n > rows[y].chars.len() {
+        n -= rows[y].chars.len() - b + 1;
+        y += 1;
+        b = 0;
+    }
+    (y, b + n)
+}
+
+/// Matches in `rows[first..last]`, as spans with absolute row numbers.
+fn find_in_rows(
+    rows: &[ERow],
+    first: usize,
+    last: usize,
+    regex: &Regex,
+    whole_word: bool,
+) -> Vec<Span> {
+    // No rows is not even an empty line for a regex like `^` to match
+    if first >= last {
+        return Vec::new();
+    }
+    let (text, starts) = rows_text(&rows[first..last]);
+    find_all_matches(regex, whole_word, &text)
+        .into_iter()
+        .map(|(start, end)| {
+            let (sy, sb) = text_pos(&starts, start);
+            let (ey, eb) = text_pos(&starts, end);
+            ((first + sy, sb), (first + ey, eb))
+        })
+        .collect()
+}
+
+/// The part of `span` on row `y`, as a byte range in `render`.
+fn span_render_range(
+    row: &ERow,
+    y: usize,
+    ((sy, sb), (ey, eb)): Span,
+    tab_stop: usize,
+) -> Option<(usize, usize)> {
+    if y < sy || y > ey {
+        return None;
+    }
+    let render_byte = |b: usize| {
+        let cx = row.chars[..b.min(row.chars.len())].chars().count();
+        let idx = editor_row_cx_to_render_idx(row, cx, tab_stop);
+        row.render
+            .char_indices()
+            .nth(idx)
+            .map_or(row.render.len(), |(i, _)| i)
+    };
+    let start = if y == sy { render_byte(sb) } else { 0 };
+    let end = if y == ey {
+        render_byte(eb)
+    } else {
+        row.render.len()
+    };
+    Some((start, end))
+}
+
+fn editor_find_options_hint(e: &EditorConfig) -> String {
+    let mut options = vec![match e.find.case {
+        CaseMode::Smart => "smart case",
+        CaseMode::Sensitive => "match case",
+        CaseMode::Insensitive => "ignore case",
+    }];
+    if e.find.regex {
+        options.push("regex");
+    }
+    if e.find.whole_word {
+        options.push("whole word");
+    }
+    format!("  [{}]", options.join(", "))
+}
+
+fn editor_find_callback(e: &mut EditorConfig, query: &str, key: &EditorKey) {
+    match key {
+        EditorKey::Escape | EditorKey::Return => {
+            e.find.matches.clear();
+            e.find.current = None;
+            e.find.current_match = None;
+            e.find.prompting = false;
+            return;
+        }
+        EditorKey::Alt('r') => e.find.regex = !e.find.regex,
+        EditorKey::Alt('c') => {
+            e.find.case = match e.find.case {
+                CaseMode::Smart => CaseMode::Sensitive,
+                CaseMode::Sensitive => CaseMode::Insensitive,
+                CaseMode::Insensitive => CaseMode::Smart,
             }
         }
+        EditorKey::Alt('w') => e.find.whole_word = !e.find.whole_word,
+        _ => (),
+    }
+
+    e.prompt_hint = editor_find_options_hint(e);
+    let regex = match editor_find_regex(e, query) {
+        Ok(regex) => regex,
+        Err(_) => {
+            e.prompt_hint += " invalid regex";
+            return;
+        }
+    };
+    if query.is_empty() {
+        e.find.matches.clear();
+        e.find.current = None;
+        e.find.current_match = None;
+        e.find.last_search = None;
+        return;
+    }
+
+    // Keys that only edit the query, like cursor movement, may leave it as it was. The query
+    // may also have been replaced from the history with Alt-P or Alt-N.
+    let toggled = matches!(
+        key,
+        EditorKey::Alt('r') | EditorKey::Alt('c') | EditorKey::Alt('w')
+    );
+    let fresh = toggled || query != e.find.query || e.find.current.is_none();
+    if fresh {
+        let rows = &e.buf.rows;
+        e.find.matches = find_in_rows(rows, 0, rows.len(), &regex, e.find.whole_word);
+        e.find.query = query.to_string();
+    }
+    e.find.last_search = Some((regex, e.find.whole_word));
+
+    let count = e.find.matches.len();
+    if count == 0 {
+        e.find.current = None;
+        e.find.current_match = None;
+        e.prompt_hint += " no matches";
+        return;
     }
+    let step = match key {
+        EditorKey::ArrowUp => -1,
+        EditorKey::ArrowDown => 1,
+        _ => 0,
+    };
+    let current = match e.find.current {
+        Some(current) if !fresh => (current as isize + step).rem_euclid(count as isize) as usize,
+        _ => {
+            let (cx, cy) = editor_clamp_pos(e, e.find.origin);
+            let origin = (cy, e.buf.rows[cy].byte_idx(cx));
+            e.find
+                .matches
+                .iter()
+                .position(|&(start, _)| start >= origin)
+                .unwrap_or(0)
+        }
+    };
+    e.find.current = Some(current);
+    e.prompt_hint += &format!(" match {} of {}", current + 1, count);
+
+    let span = e.find.matches[current];
+    let ((y, b), _) = span;
+    e.cy = y;
+    e.cx = e.buf.rows[y].chars[..b].chars().count();
+    e.rowoff = e.buf.rows.len();
+    e.find.current_match = Some(span);
 }
 
 fn editor_find(e: &mut EditorConfig) {
@@ -775,9 +3268,13 @@ fn editor_find(e: &mut EditorConfig) {
     let saved_rowoff = e.rowoff;
     let saved_coloff = e.coloff;
 
+    e.prompt_hint = editor_find_options_hint(e);
+    e.find.origin = (e.cx, e.cy);
+    e.find.prompting = true;
     if editor_prompt(
         e,
-        "Search (Use ESC/Arrows/Enter): ",
+        "Search (Up/Down, Alt-P/N history, Alt-R regex, Alt-C case, Alt-W word): ",
+        PromptKind::Search,
         Some(editor_find_callback),
     )
     .is_none()
@@ -789,13 +3286,130 @@ fn editor_find(e: &mut EditorConfig) {
     }
 }
 
+/// Replaces matches from the cursor to the end of the buffer, then from the top back to the
+/// cursor, asking about each one.
+fn editor_replace(e: &mut EditorConfig) {
+    let (saved_cx, saved_cy) = (e.cx, e.cy);
+    let (saved_rowoff, saved_coloff) = (e.rowoff, e.coloff);
+
+    e.prompt_hint = editor_find_options_hint(e);
+    e.find.origin = (e.cx, e.cy);
+    e.find.prompting = true;
+    let query = editor_prompt(
+        e,
+        "Replace: ",
+        PromptKind::Search,
+        Some(editor_find_callback),
+    );
+    // The preview moved the cursor; start from where the user was
+    e.cx = saved_cx;
+    e.cy = saved_cy.min(e.buf.rows.len());
+    e.rowoff = saved_rowoff;
+    e.coloff = saved_coloff;
+    let query = match query {
+        Some(query) => query,
+        None => return,
+    };
+    let regex = match editor_find_regex(e, &query) {
+        Ok(regex) => regex,
+        Err(err) => {
+            editor_set_status_message(e, &format!("Invalid regex: {}", err));
+            return;
+        }
+    };
+    let replacement = match editor_prompt(
+        e,
+        &format!("Replace {} with: ", query),
+        PromptKind::Replacement,
+        None,
+    ) {
+        Some(replacement) => unescape(&replacement),
+        None => return,
+    };
+    if e.buf.rows.is_empty() {
+        editor_set_status_message(e, "Replaced 0 occurrences");
+        return;
+    }
+    // Show the other matches while asking
+    e.find.last_search = Some((regex.clone(), e.find.whole_word));
+    e.find.prompting = true;
+
+    // All matches are found up front in the original text. Those after the cursor come first,
+    // then the ones before it, so replacing one only moves the ones after it in this order.
+    let (text, starts) = rows_text(&e.buf.rows);
+    let (start_cx, start_y) = editor_clamp_pos(e, (e.cx, e.cy));
+    let origin_pos = (start_y, e.buf.rows[start_y].byte_idx(start_cx));
+    let origin = starts[start_y] + origin_pos.1;
+    let matches = find_all_matches(&regex, e.find.whole_word, &text);
+    let (before, after): (Vec<_>, Vec<_>) =
+        matches.into_iter().partition(|&(start, _)| start < origin);
+    let before = before.into_iter().filter(|&(_, end)| end <= origin);
+
+    let mut replace_all = false;
+    let mut count = 0;
+    'phases: for (phase, anchor) in [
+        (after, (origin, origin_pos)),
+        (before.collect(), (0, (0, 0))),
+    ] {
+        // An offset in the original text and where it is now; the text between it and the
+        // next match is unchanged, so matches are found by walking forward from it
+        let mut anchor = anchor;
+        for (start, end) in phase {
+            let (sy, sb) = text_advance(&e.buf.rows, anchor.1, start - anchor.0);
+            let (ey, eb) = text_advance(&e.buf.rows, (sy, sb), end - start);
+            anchor = (end, (ey, eb));
+            let cx = e.buf.rows[sy].chars[..sb].chars().count();
+            let cx_end = e.buf.rows[ey].chars[..eb].chars().count();
+            e.cx = cx;
+            e.cy = sy;
+
+            if !replace_all {
+                e.find.current_match = Some(((sy, sb), (ey, eb)));
+                let choice = editor_prompt_choice(e, "Replace? (y)es (n)o (a)ll (q)uit", "ynaq");
+                e.find.current_match = None;
+                match choice {
+                    Some('y') => (),
+                    Some('a') => replace_all = true,
+                    Some('n') => continue,
+                    _ => break 'phases,
+                }
+            }
+
+            let replacement = if e.find.regex {
+                let mut expanded = String::new();
+                if let Some(caps) = regex.captures_at(&text, start) {
+                    caps.expand(&replacement, &mut expanded);
+                }
+                expanded
+            } else {
+                replacement.clone()
+            };
+            editor_delete_region(e, (cx, sy), (cx_end, ey));
+            editor_insert_text(e, &replacement);
+            count += 1;
+            anchor.1 = (e.cy, e.buf.rows[e.cy].byte_idx(e.cx));
+        }
+    }
+    e.find.prompting = false;
+
+    editor_set_status_message(
+        e,
+        &format!(
+            "Replaced {} occurrence{}",
+            count,
+            if count == 1 { "" } else { "s" }
+        ),
+    );
+}
+
 /*** output ***/
 
 fn editor_scroll(e: &mut EditorConfig) {
     e.rx = e
+        .buf
         .rows
         .get(e.cy)
-        .map(|r| editor_row_cx_to_rx(r, e.cx))
+        .map(|r| editor_row_cx_to_rx(r, e.cx, e.tab_stop))
         .unwrap_or(0);
 
     if e.cy < e.rowoff {
@@ -807,188 +3421,853 @@ fn editor_scroll(e: &mut EditorConfig) {
     if e.rx < e.coloff {
         e.coloff = e.rx;
     }
-    if e.rx >= e.coloff + e.screencols {
-        e.coloff = e.rx - e.screencols + 1;
+    let cursor_width = e
+        .buf
+        .rows
+        .get(e.cy)
+        .and_then(|r| r.graphemes().find(|(at, _)| *at == e.cx))
+        .map(|(_, g)| if g == "\t" { 1 } else { grapheme_width(g) })
+        .unwrap_or(1);
+    if e.rx + cursor_width > e.coloff + e.screencols {
+        e.coloff = e.rx + cursor_width - e.screencols;
+    }
+}
+
+fn flush_stdout() {
+    unwrap_or_die("flush_stdout", std::io::stdout().flush())
+}
+
+fn editor_refresh_screen(e: &mut EditorConfig) {
+    editor_scroll(e);
+    editor_save_window(e);
+
+    let mut buffer = String::new();
+
+    buffer += "\x1b[?25l";
+    buffer += "\x1b[H";
+
+    for (idx, w) in e.windows.iter().enumerate() {
+        editor_draw_rows(e, w, idx == e.current_window, &mut buffer);
+        editor_draw_status_bar(e, w, idx == e.current_window, &mut buffer);
+    }
+    for (top, col, height) in &e.separators {
+        for y in *top..top + height {
+            buffer += &format!("\x1b[{};{}H\x1b[7m \x1b[m", y + 1, col + 1);
+        }
+    }
+    editor_draw_message_bar(e, &mut buffer);
+
+    buffer += &match e.prompt_cursor {
+        Some(col) => format!("\x1b[{};{}H", e.term_rows, col + 1),
+        None => format!(
+            "\x1b[{};{}H",
+            e.screentop + e.cy - e.rowoff + 1,
+            e.screenleft + (e.rx - e.coloff) + 1
+        ),
+    };
+
+    buffer += "\x1b[?25h";
+
+    print!("{}", buffer);
+
+    flush_stdout();
+}
+
+fn editor_set_status_message(e: &mut EditorConfig, msg: &str) {
+    // Not taking a format string, because unlike C, variadics are hard, and also format! is easy
+    // to use at the call-site
+    e.statusmsg = msg.into();
+    e.statusmsg_time = Instant::now();
+}
+
+fn editor_draw_rows(e: &EditorConfig, w: &Window, active: bool, buffer: &mut String) {
+    let rows = &editor_buffer(e, w.buffer).rows;
+    let selection = if active { editor_selection(e) } else { None };
+    // Matches that start in the window
+    let matches = match &e.find.last_search {
+        Some((regex, whole_word))
+            if (e.find.prompting || e.find.highlight) && w.buffer == e.current =>
+        {
+            let first = w.rowoff.min(rows.len());
+            let last = (w.rowoff + w.height).min(rows.len());
+            find_in_rows(rows, first, last, regex, *whole_word)
+        }
+        _ => Vec::new(),
+    };
+    for y in 0..w.height {
+        *buffer += &format!("\x1b[{};{}H", w.top + y + 1, w.left + 1);
+        // Columns drawn so far; the rest of the line is cleared with spaces, since there may be
+        // another window to the right
+        let mut drawn = 0;
+        let filerow = y + w.rowoff;
+        if filerow >= rows.len() {
+            if rows.is_empty() && y == w.height / 3 {
+                let mut msg = format!("BYOTE -- version {}", BYOTE_VERSION.unwrap_or("unknown"));
+                msg.truncate(w.width);
+                let mut padding = (w.width - msg.len()) / 2;
+                if padding > 0 {
+                    *buffer += "~";
+                    padding -= 1;
+                    drawn += 1;
+                }
+                while padding > 0 {
+                    *buffer += " ";
+                    padding -= 1;
+                    drawn += 1;
+                }
+
+                *buffer += &msg;
+                drawn += msg.len();
+            } else {
+                *buffer += "~";
+                drawn += 1;
+            }
+        } else {
+            let row = &rows[filerow];
+            // Selected screen columns of this row, before scrolling
+            let (sel_start, sel_end) = match selection {
+                Some(((sx, sy), (ex, ey))) if sy <= filerow && filerow <= ey => (
+                    if filerow == sy {
+                        editor_row_cx_to_rx(row, sx, e.tab_stop)
+                    } else {
+                        0
+                    },
+                    if filerow == ey {
+                        editor_row_cx_to_rx(row, ex, e.tab_stop)
+                    } else {
+                        usize::MAX
+                    },
+                ),
+                _ => (0, 0),
+            };
+            let row_matches: Vec<_> = matches
+                .iter()
+                .filter_map(|&span| span_render_range(row, filerow, span, e.tab_stop))
+                .collect();
+            let current_match = match e.find.current_match {
+                Some(span) if active => {
+                    span_render_range(row, filerow, span, e.tab_stop).unwrap_or((0, 0))
+                }
+                _ => (0, 0),
+            };
+            let mut current_color: i8 = -1;
+            // `col` is the screen column (before scrolling) where the next cluster starts,
+            // `i` is its index into `row.hl`, `byte` into `row.render`
+            let mut col = 0;
+            let mut i = 0;
+            let mut byte = 0;
+            for g in row.render.graphemes(true) {
+                let width = grapheme_width(g);
+                let hl = if sel_start <= col && col < sel_end {
+                    &Highlight::Selection
+                } else if current_match.0 <= byte && byte < current_match.1 {
+                    &Highlight::CurrentMatch
+                } else if row_matches
+                    .iter()
+                    .any(|&(start, end)| start <= byte && byte < end)
+                {
+                    &Highlight::Match
+                } else {
+                    &row.hl[i]
+                };
+                i += g.chars().count();
+                byte += g.len();
+
+                if col < w.coloff {
+                    // A wide character cut in half by the left edge of the screen
+                    if col + width > w.coloff {
+                        let visible = (col + width - w.coloff).min(w.width);
+                        *buffer += &" ".repeat(visible);
+                        drawn += visible;
+                    }
+                    col += width;
+                    continue;
+                }
+                if col + width > w.coloff + w.width {
+                    break;
+                }
+                col += width;
+                drawn += width;
+
+                let c = g.chars().next().unwrap_or(' ');
+                if let Some(b) = char_to_raw_byte(c) {
+                    *buffer += &format!("\x1b[7m<{:02X}>\x1b[m", b);
+                    if current_color != -1 {
+                        *buffer += &format!("\x1b[{}m", current_color);
+                    }
+                } else if c.is_ascii_control() || g.width() == 0 {
+                    let sym = if (c as u32) < 26 {
+                        (b'@' + (c as u8)) as char
+                    } else {
+                        '?'
+                    };
+                    *buffer += "\x1b[7m";
+                    buffer.push(sym);
+                    *buffer += "\x1b[m";
+                    if current_color != -1 {
+                        *buffer += &format!("\x1b[{}m", current_color);
+                    }
+                } else if *hl == Highlight::Normal {
+                    if current_color != -1 {
+                        *buffer += "\x1b[m";
+                        current_color = -1;
+                    }
+                    *buffer += g;
+                } else {
+                    let color = editor_syntax_to_color(e, hl);
+                    if current_color as u8 != color {
+                        // Start from the defaults, a color may be a background or inverse
+                        current_color = color as i8;
+                        *buffer += &format!("\x1b[m\x1b[{}m", color);
+                    }
+                    *buffer += g;
+                }
+            }
+
+            *buffer += "\x1b[m";
+        }
+        *buffer += &" ".repeat(w.width.saturating_sub(drawn));
+    }
+}
+
+fn editor_draw_status_bar(e: &EditorConfig, w: &Window, active: bool, buffer: &mut String) {
+    let b = editor_buffer(e, w.buffer);
+    *buffer += &format!("\x1b[{};{}H", w.top + w.height + 1, w.left + 1);
+    // Windows other than the current one get a dimmed status bar
+    *buffer += if active { "\x1b[7m" } else { "\x1b[2;7m" };
+
+    let shown_filename = truncate_to_width(b.name(), 20);
+    let buffer_number = if e.buffers.len() > 1 {
+        format!("[{}/{}] ", w.buffer + 1, e.buffers.len())
+    } else {
+        String::new()
+    };
+    let status = format!(
+        "{}{} - {} lines {}",
+        buffer_number,
+        shown_filename,
+        b.rows.len(),
+        if b.dirty { "(modified)" } else { "" }
+    );
+    let rstatus = format!(
+        "{} | {}{} | {}/{}",
+        b.syntax.as_ref().map_or("no ft", |s| s.filetype.as_str()),
+        b.line_ending.name(),
+        if b.final_newline { "" } else { " noeol" },
+        w.cy + 1,
+        b.rows.len()
+    );
+
+    let status = truncate_to_width(&status, w.width);
+    let status_len = str_width(&status);
+    let rstatus_len = str_width(&rstatus);
+    *buffer += &status;
+    if w.width >= status_len + rstatus_len {
+        *buffer += &" ".repeat(w.width - status_len - rstatus_len);
+        *buffer += &rstatus;
+    } else {
+        *buffer += &" ".repeat(w.width - status_len);
+    }
+    *buffer += "\x1b[m";
+}
+
+fn editor_draw_message_bar(e: &EditorConfig, buffer: &mut String) {
+    *buffer += &format!("\x1b[{};1H\x1b[K", e.term_rows);
+    if !e.statusmsg.is_empty() && Instant::now() < e.statusmsg_time + e.status_timeout {
+        *buffer += &truncate_to_width(&e.statusmsg, e.term_cols);
+    }
+}
+
+/*** config ***/
+
+fn config_dir() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("byote"))
+}
+
+enum ConfigValue {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<ConfigValue>),
+}
+
+// An array value goes on until its closing bracket, even across lines
+#[derive(Debug)]
+struct UnterminatedArray;
+
+impl std::fmt::Display for UnterminatedArray {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "unterminated array")
+    }
+}
+
+impl std::error::Error for UnterminatedArray {}
+
+/// Parses the value of an option: an integer, `true`, `false`, a double-quoted string or an
+/// array of these, optionally followed by a comment.
+fn parse_config_value(s: &str) -> Result<ConfigValue> {
+    let (value, rest) = parse_config_value_prefix(s)?;
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        bail!(format!("unexpected `{}` after value", rest));
     }
+    Ok(value)
 }
 
-fn flush_stdout() {
-    unwrap_or_die("flush_stdout", std::io::stdout().flush())
+/// Skips whitespace, line breaks and comments.
+fn skip_config_blanks(mut s: &str) -> &str {
+    loop {
+        s = s.trim_start();
+        match s.strip_prefix('#') {
+            Some(comment) => s = comment.split_once('\n').map_or("", |(_, rest)| rest),
+            None => return s,
+        }
+    }
 }
 
-fn editor_refresh_screen(e: &mut EditorConfig) {
-    editor_scroll(e);
+/// Parses the value at the start of `s`, returning it and what follows.
+fn parse_config_value_prefix(s: &str) -> Result<(ConfigValue, &str)> {
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = skip_config_blanks(rest);
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Ok((ConfigValue::Array(items), rest));
+            }
+            if rest.is_empty() {
+                return Err(UnterminatedArray.into());
+            }
+            let (item, after) = parse_config_value_prefix(rest)?;
+            items.push(item);
+            rest = skip_config_blanks(after);
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.is_empty() && !rest.starts_with(']') {
+                bail!("expected `,` or `]` in array");
+            }
+        }
+    }
 
-    let mut buffer = String::new();
+    let mut chars = match s.strip_prefix('"') {
+        Some(rest) => rest.chars(),
+        None => {
+            let end = s
+                .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+                .unwrap_or(s.len());
+            let value = match &s[..end] {
+                "true" => ConfigValue::Bool(true),
+                "false" => ConfigValue::Bool(false),
+                word => match word.parse() {
+                    Ok(n) => ConfigValue::Int(n),
+                    Err(_) => bail!(format!("invalid value `{}`", word)),
+                },
+            };
+            return Ok((value, &s[end..]));
+        }
+    };
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Ok((ConfigValue::Str(out), chars.as_str())),
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) if c == '"' || c == '\\' => out.push(c),
+                _ => bail!("invalid escape in string"),
+            },
+            '\n' => break,
+            c => out.push(c),
+        }
+    }
+    bail!("unterminated string")
+}
 
-    buffer += "\x1b[?25l";
-    buffer += "\x1b[H";
+/// Goes through the options in `data`, a small subset of TOML: `name = value` lines and
+/// `[table]` headers. Calls `set` with the table (empty at the top), name and value of each.
+/// Returns a `path:line: message` for each option that couldn't be set.
+fn parse_config(
+    data: &str,
+    path: &Path,
+    mut set: impl FnMut(&str, &str, ConfigValue) -> Result<()>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut table = "";
+    let mut lines = data.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line = line.trim();
+        let result = if line.is_empty() || line.starts_with('#') {
+            Ok(())
+        } else if line.starts_with('[') {
+            match line
+                .split('#')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .strip_suffix(']')
+            {
+                Some(name) => {
+                    table = name[1..].trim();
+                    Ok(())
+                }
+                None => Err("expected `[table]`".into()),
+            }
+        } else {
+            // Names with spaces or punctuation, like key sequences, are quoted
+            let (name, rest) = match line.strip_prefix('"') {
+                Some(quoted) => match quoted.split_once('"') {
+                    Some((name, rest)) => (Some(name), rest),
+                    None => (None, ""),
+                },
+                None => match line.find('=') {
+                    Some(i) => (Some(line[..i].trim()), &line[i..]),
+                    None => (None, ""),
+                },
+            };
+            match (name, rest.trim_start().strip_prefix('=')) {
+                (Some(name), Some(value)) => {
+                    let mut value = value.trim().to_string();
+                    let mut parsed = parse_config_value(&value);
+                    while matches!(&parsed, Err(err) if err.is::<UnterminatedArray>()) {
+                        match lines.next() {
+                            Some((_, line)) => {
+                                value.push('\n');
+                                value += line;
+                                parsed = parse_config_value(&value);
+                            }
+                            None => break,
+                        }
+                    }
+                    parsed.and_then(|value| set(table, name, value))
+                }
+                _ => Err("expected `name = value`".into()),
+            }
+        };
+        if let Err(err) = result {
+            errors.push(format!("{}:{}: {}", path.display(), i + 1, err));
+        }
+    }
+    errors
+}
 
-    editor_draw_rows(e, &mut buffer);
-    editor_draw_status_bar(e, &mut buffer);
-    editor_draw_message_bar(e, &mut buffer);
+/// Shows the first of `errors` on the status bar; returns whether there were none.
+fn editor_report_config_errors(e: &mut EditorConfig, errors: &[String]) -> bool {
+    match errors.len() {
+        0 => true,
+        1 => {
+            editor_set_status_message(e, &errors[0]);
+            false
+        }
+        n => {
+            let more = if n == 2 { "error" } else { "errors" };
+            let msg = format!("{} (and {} more {})", errors[0], n - 1, more);
+            editor_set_status_message(e, &msg);
+            false
+        }
+    }
+}
 
-    buffer += &format!("\x1b[{};{}H", e.cy - e.rowoff + 1, (e.rx - e.coloff) + 1);
+fn config_int(name: &str, value: &ConfigValue, min: i64, max: i64) -> Result<i64> {
+    match *value {
+        ConfigValue::Int(n) if min <= n && n <= max => Ok(n),
+        _ => bail!(format!(
+            "`{}` must be a number from {} to {}",
+            name, min, max
+        )),
+    }
+}
 
-    buffer += "\x1b[?25h";
+fn config_bool(name: &str, value: &ConfigValue) -> Result<bool> {
+    match *value {
+        ConfigValue::Bool(b) => Ok(b),
+        _ => bail!(format!("`{}` must be true or false", name)),
+    }
+}
 
-    print!("{}", buffer);
+fn config_string(name: &str, value: &ConfigValue) -> Result<String> {
+    match value {
+        ConfigValue::Str(s) => Ok(s.clone()),
+        _ => bail!(format!("`{}` must be a string", name)),
+    }
+}
 
-    flush_stdout();
+fn config_strings(name: &str, value: &ConfigValue) -> Result<Vec<String>> {
+    match value {
+        ConfigValue::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                ConfigValue::Str(s) => Ok(s.clone()),
+                _ => bail!(format!("`{}` must be an array of strings", name)),
+            })
+            .collect(),
+        _ => bail!(format!("`{}` must be an array of strings", name)),
+    }
 }
 
-fn editor_set_status_message(e: &mut EditorConfig, msg: &str) {
-    // Not taking a format string, because unlike C, variadics are hard, and also format! is easy
-    // to use at the call-site
-    e.statusmsg = msg.into();
-    e.statusmsg_time = Instant::now();
+/// An empty string turns the option off.
+fn config_command(name: &str, value: &ConfigValue) -> Result<Option<String>> {
+    match value {
+        ConfigValue::Str(s) => Ok(Some(s.clone()).filter(|s| !s.is_empty())),
+        _ => bail!(format!("`{}` must be a string", name)),
+    }
 }
 
-#[allow(clippy::print_with_newline)]
-fn editor_draw_rows(e: &EditorConfig, buffer: &mut String) {
-    for y in 0..e.screenrows {
-        let filerow = y + e.rowoff;
-        if filerow >= e.rows.len() {
-            if e.rows.is_empty() && y == e.screenrows / 3 {
-                let mut msg = format!("BYOTE -- version {}", BYOTE_VERSION.unwrap_or("unknown"));
-                msg.truncate(e.screencols);
-                let mut padding = (e.screencols - msg.len()) / 2;
-                if padding > 0 {
-                    *buffer += "~";
-                    padding -= 1;
-                }
-                while padding > 0 {
-                    *buffer += " ";
-                    padding -= 1;
-                }
+fn highlight_by_name(name: &str) -> Option<Highlight> {
+    Some(match name {
+        "comment" => Highlight::Comment,
+        "mlcomment" => Highlight::MLComment,
+        "keyword1" => Highlight::Keyword1,
+        "keyword2" => Highlight::Keyword2,
+        "keyword3" => Highlight::Keyword3,
+        "string" => Highlight::String,
+        "number" => Highlight::Number,
+        "match" => Highlight::Match,
+        "current_match" => Highlight::CurrentMatch,
+        "selection" => Highlight::Selection,
+        _ => return None,
+    })
+}
 
-                *buffer += &msg;
-            } else {
-                *buffer += "~";
+fn editor_config_set(
+    e: &mut EditorConfig,
+    table: &str,
+    name: &str,
+    value: &ConfigValue,
+) -> Result<()> {
+    match table {
+        "" => match name {
+            "tab_stop" => e.tab_stop = config_int(name, value, 1, 32)? as usize,
+            "quit_times" => e.confirm_quit_times = config_int(name, value, 0, 255)? as u8,
+            "status_timeout" => {
+                e.status_timeout = Duration::from_secs(config_int(name, value, 1, 3600)? as u64)
             }
-        } else {
-            let row = &e.rows[filerow];
-            let len = row
-                .render
-                .len()
-                .checked_sub(e.coloff)
-                .unwrap_or(0)
-                .min(e.screencols);
-            if len > 0 {
-                let s = &row.render[e.coloff..len];
-                let hls = &row.hl[e.coloff..len];
-                let mut current_color: i8 = -1;
-                for (c, hl) in s.chars().zip(hls) {
-                    if c.is_ascii_control() {
-                        let sym = if (c as u8) < 26 {
-                            (b'@' + (c as u8)) as char
-                        } else {
-                            '?'
-                        };
-                        *buffer += "\x1b[7m";
-                        buffer.push(sym);
-                        *buffer += "\x1b[m";
-                        if current_color != -1 {
-                            *buffer += &format!("\x1b[{}m", current_color);
-                        }
-                    } else if *hl == Highlight::Normal {
-                        if current_color != -1 {
-                            *buffer += "\x1b[39m";
-                            current_color = -1;
-                        }
-                        buffer.push(c);
-                    } else {
-                        let color = editor_syntax_to_color(hl);
-                        if current_color as u8 != color {
-                            current_color = color as i8;
-                            *buffer += &format!("\x1b[{}m", color);
+            "backup_files" => e.backup_files = config_bool(name, value)?,
+            "osc52" => e.osc52 = config_bool(name, value)?,
+            "clipboard_copy_command" => e.clipboard_copy_command = config_command(name, value)?,
+            "clipboard_paste_command" => e.clipboard_paste_command = config_command(name, value)?,
+            _ => bail!(format!("unknown option `{}`", name)),
+        },
+        // SGR parameters, e.g. 31 for a red foreground or 44 for a blue background
+        "colors" => match highlight_by_name(name) {
+            Some(hl) => {
+                let color = config_int(&format!("colors.{}", name), value, 0, 107)?;
+                e.colors.insert(hl, color as u8);
+            }
+            None => bail!(format!("unknown color `{}`", name)),
+        },
+        // Key sequences like "C-x C-s"; binding to "" removes a binding
+        "keys" => {
+            let keys = match parse_keys(name) {
+                Some(keys) => keys,
+                None => bail!(format!("invalid key sequence `{}`", name)),
+            };
+            match value {
+                ConfigValue::Str(command) if command.is_empty() => {
+                    e.keymap.remove(&keys);
+                }
+                ConfigValue::Str(command) => match COMMANDS.iter().find(|c| c.name == command) {
+                    // A binding would hide the ones it's the start of, or that start it
+                    Some(command) => {
+                        let conflict = e.keymap.keys().find(|bound| {
+                            *bound != &keys && (bound.starts_with(&keys) || keys.starts_with(bound))
+                        });
+                        if let Some(bound) = conflict {
+                            let bound = keys_name(bound);
+                            bail!(format!(
+                                "`{}` is already bound; unbind it with `\"{}\" = \"\"` first",
+                                bound, bound
+                            ));
                         }
-                        buffer.push(c);
+                        e.keymap.insert(keys, command);
                     }
-                }
+                    None => bail!(format!("unknown command `{}`", command)),
+                },
+                _ => bail!(format!("`keys.{}` must be a command name", name)),
             }
+        }
+        _ => bail!(format!("unknown option `{}.{}`", table, name)),
+    }
+    Ok(())
+}
 
-            *buffer += "\x1b[39m";
+/// Resets the settings and applies the config file: `[colors]` has the colors of the highlight
+/// classes and `[keys]` the key bindings. Returns the options that couldn't be applied.
+fn editor_config_load(e: &mut EditorConfig) -> Vec<String> {
+    e.tab_stop = BYOTE_TAB_STOP;
+    e.confirm_quit_times = BYOTE_QUIT_TIMES;
+    e.status_timeout = Duration::from_secs(BYOTE_STATUS_TIMEOUT_SECS);
+    e.colors.clear();
+    e.keymap = default_keymap();
+    e.backup_files = BYOTE_BACKUP_FILES;
+    e.osc52 = BYOTE_OSC52;
+    e.clipboard_copy_command = BYOTE_CLIPBOARD_COPY_COMMAND.map(String::from);
+    e.clipboard_paste_command = BYOTE_CLIPBOARD_PASTE_COMMAND.map(String::from);
+
+    let errors = match config_dir().map(|dir| dir.join("config.toml")) {
+        Some(path) => match std::fs::read_to_string(&path) {
+            Ok(data) => parse_config(&data, &path, |table, name, value| {
+                editor_config_set(e, table, name, &value)
+            }),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => vec![format!("Can't read {}: {}", path.display(), err)],
+        },
+        None => Vec::new(),
+    };
+    // The countdown of the next quit starts from the configured count
+    e.quit_times = e.confirm_quit_times;
+    errors
+}
+
+fn editor_config_reload(e: &mut EditorConfig) {
+    let tab_stop = e.tab_stop;
+    let errors = editor_config_load(e);
+    if editor_report_config_errors(e, &errors) {
+        editor_set_status_message(e, "Config reloaded");
+    }
+    if e.tab_stop != tab_stop {
+        for idx in 0..e.buffers.len() {
+            if idx != e.current {
+                std::mem::swap(&mut e.buf, &mut e.buffers[idx]);
+            }
+            for at in 0..e.buf.rows.len() {
+                editor_update_row(e, at);
+            }
+            if idx != e.current {
+                std::mem::swap(&mut e.buf, &mut e.buffers[idx]);
+            }
         }
-        *buffer += "\x1b[K";
-        *buffer += "\r\n";
     }
 }
 
-fn editor_draw_status_bar(e: &EditorConfig, buffer: &mut String) {
-    *buffer += "\x1b[7m";
+/*** history ***/
 
-    let shown_filename: String = e
-        .filename
-        .clone()
-        .map(|s| s.chars().take(20).collect())
-        .unwrap_or_else(|| "[No Name]".into());
-    let status = format!(
-        "{} - {} lines {}",
-        shown_filename,
-        e.rows.len(),
-        if e.dirty { "(modified)" } else { "" }
-    );
-    let rstatus = format!(
-        "{} | {}/{}",
-        e.syntax.map(|s| s.filetype).unwrap_or("no ft"),
-        e.cy + 1,
-        e.rows.len()
-    );
+fn history_path() -> Option<PathBuf> {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(data_dir.join("byote").join("history"))
+}
 
-    *buffer += &status[..=e.screencols.min(status.len() - 1)];
-    *buffer += &" ".repeat(e.screencols - status.len() - rstatus.len());
-    *buffer += &rstatus;
-    *buffer += "\x1b[m";
-    *buffer += "\r\n";
+/// Reads the prompt history saved by earlier sessions: one `kind<TAB>entry` per line.
+fn editor_history_load(e: &mut EditorConfig) {
+    let data = match history_path().map(std::fs::read_to_string) {
+        Some(Ok(data)) => data,
+        _ => return,
+    };
+    for line in data.lines() {
+        let (name, entry) = match line.split_once('\t') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        if let Some(kind) = PromptKind::ALL.iter().find(|kind| kind.name() == name) {
+            e.history.entry(*kind).or_default().push(entry.to_string());
+        }
+    }
 }
 
-fn editor_draw_message_bar(e: &EditorConfig, buffer: &mut String) {
-    *buffer += "\x1b[K";
-    if !e.statusmsg.is_empty() && Instant::now() < e.statusmsg_time + Duration::from_secs(5) {
-        let cropped_msg: String = e.statusmsg.chars().take(e.screencols).collect();
-        *buffer += &cropped_msg;
+fn editor_history_save(e: &EditorConfig) -> std::io::Result<()> {
+    let path = match history_path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let mut data = String::new();
+    for kind in PromptKind::ALL.iter() {
+        for entry in e.history.get(kind).into_iter().flatten() {
+            data += &format!("{}\t{}\n", kind.name(), entry);
+        }
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_file_atomically(&path.to_string_lossy(), data.as_bytes(), false, true).map(|_| ())
+}
+
+/// Remembers an answer, moving it to the end if it was already there.
+fn editor_history_add(e: &mut EditorConfig, kind: PromptKind, entry: &str) {
+    if entry.is_empty() || entry.contains('\n') {
+        return;
+    }
+    let history = e.history.entry(kind).or_default();
+    history.retain(|old| old != entry);
+    history.push(entry.to_string());
+    if history.len() > BYOTE_HISTORY_SIZE {
+        history.remove(0);
+    }
+    // Other sessions may have added to the file since we read it; the last one to save wins
+    if let Err(err) = editor_history_save(e) {
+        editor_set_status_message(e, &format!("Can't save prompt history: {}", err));
     }
 }
 
 /*** input ***/
 
+/// Reads a line of input on the message bar, with the usual line editing keys. Alt-P and Alt-N
+/// recall earlier answers of the same kind, and so do Up and Down unless there is a callback,
+/// which gets them instead. Tab completes file names.
 fn editor_prompt(
     e: &mut EditorConfig,
     prompt: &str,
+    kind: PromptKind,
     callback: Option<PromptCallback>,
 ) -> Option<String> {
+    // A replacement may be empty to delete what matched
+    let allow_empty = kind == PromptKind::Replacement;
+    let history = e.history.get(&kind).cloned().unwrap_or_default();
+    // The history entry being shown, and what was typed before going into the history
+    let mut history_idx: Option<usize> = None;
+    let mut typed = String::new();
     let mut buf = String::with_capacity(128);
+    // Byte offset of the cursor in `buf`, always at a grapheme boundary
+    let mut pos = 0;
+    // The directory and the file names offered by the last Tab, and the one shown
+    let mut completion: Option<(String, Vec<String>, Option<usize>)> = None;
     loop {
-        editor_set_status_message(e, &format!("{}{}", prompt, &buf));
+        editor_draw_prompt(e, prompt, &buf, pos);
         editor_refresh_screen(e);
-        let k = editor_read_key();
+        let k = editor_read_key(e);
+        if kind == PromptKind::File && k != EditorKey::Char('\t') {
+            completion = None;
+            e.prompt_hint.clear();
+        }
+        let prev = buf[..pos]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i);
+        let next = buf[pos..]
+            .graphemes(true)
+            .next()
+            .map_or(pos, |g| pos + g.len());
         match k {
-            ref k if is_backspace_or_delete(k) && !buf.is_empty() => {
-                buf.remove(buf.len() - 1);
-            }
             EditorKey::Escape => {
                 editor_set_status_message(e, "");
+                e.prompt_hint.clear();
+                e.prompt_cursor = None;
                 if let Some(f) = callback {
                     f(e, &buf, &k)
                 };
                 return None;
             }
-            EditorKey::Return => {
-                if !buf.is_empty() {
-                    editor_set_status_message(e, "");
-                    if let Some(f) = callback {
-                        f(e, &buf, &k)
+            EditorKey::Return if allow_empty || !buf.is_empty() => {
+                editor_set_status_message(e, "");
+                e.prompt_hint.clear();
+                e.prompt_cursor = None;
+                if let Some(f) = callback {
+                    f(e, &buf, &k)
+                };
+                editor_history_add(e, kind, &buf);
+                if kind == PromptKind::File {
+                    return Some(expand_tilde(&buf));
+                }
+                return Some(buf);
+            }
+            EditorKey::Alt('p')
+            | EditorKey::Alt('n')
+            | EditorKey::ArrowUp
+            | EditorKey::ArrowDown
+                if callback.is_none() || matches!(k, EditorKey::Alt(_)) =>
+            {
+                let back = matches!(k, EditorKey::Alt('p') | EditorKey::ArrowUp);
+                history_idx = match (back, history_idx) {
+                    (true, None) if !history.is_empty() => {
+                        typed = buf.clone();
+                        Some(history.len() - 1)
+                    }
+                    (true, Some(i)) => Some(i.saturating_sub(1)),
+                    (false, Some(i)) if i + 1 < history.len() => Some(i + 1),
+                    _ => None,
+                };
+                buf = match history_idx {
+                    Some(i) => history[i].clone(),
+                    None => typed.clone(),
+                };
+                pos = buf.len();
+            }
+
+            EditorKey::ArrowLeft => pos = prev,
+            EditorKey::ArrowRight => pos = next,
+            EditorKey::Home => pos = 0,
+            EditorKey::End => pos = buf.len(),
+            EditorKey::Char(c) if c == ctrl_key('a') => pos = 0,
+            EditorKey::Char(c) if c == ctrl_key('e') => pos = buf.len(),
+
+            EditorKey::Delete => {
+                buf.replace_range(pos..next, "");
+            }
+            ref k if is_backspace_or_delete(k) => {
+                buf.replace_range(prev..pos, "");
+                pos = prev;
+            }
+            // Deletes the word before the cursor, and any blanks after it
+            EditorKey::Char(c) if c == ctrl_key('w') => {
+                let trimmed = buf[..pos].trim_end();
+                let start = trimmed.rfind(char::is_whitespace).map_or(0, |i| {
+                    i + trimmed[i..].chars().next().map_or(1, char::len_utf8)
+                });
+                buf.replace_range(start..pos, "");
+                pos = start;
+            }
+            EditorKey::Char(c) if c == ctrl_key('u') => {
+                buf.replace_range(..pos, "");
+                pos = 0;
+            }
+            EditorKey::Char(c) if c == ctrl_key('k') => buf.truncate(pos),
+            // Line breaks and tabs become the escapes search and replace understand, other
+            // prompts only get the first line
+            EditorKey::Char(c) if c == ctrl_key('y') => {
+                if let Some(text) = editor_kill_ring_latest(e, false) {
+                    let text = match kind {
+                        PromptKind::Search | PromptKind::Replacement => text
+                            .replace('\\', "\\\\")
+                            .replace('\n', "\\n")
+                            .replace('\t', "\\t"),
+                        _ => text.lines().next().unwrap_or("").to_string(),
                     };
-                    return Some(buf);
+                    buf.insert_str(pos, &text);
+                    pos += text.len();
+                }
+            }
+
+            // Completes the common prefix of the candidates and lists them, Tab again cycles
+            // through them
+            EditorKey::Char('\t') if kind == PromptKind::File => {
+                let (dir, names, shown) = match completion.take() {
+                    Some((dir, names, shown)) => {
+                        let i = shown.map_or(0, |i| (i + 1) % names.len());
+                        buf = format!("{}{}", dir, names[i]);
+                        (dir, names, Some(i))
+                    }
+                    None => {
+                        let (dir, names) = file_completions(&buf);
+                        if names.is_empty() {
+                            e.prompt_hint = " [no match]".to_string();
+                            continue;
+                        }
+                        buf = format!("{}{}", dir, common_prefix(&names));
+                        (dir, names, None)
+                    }
+                };
+                pos = buf.len();
+                if names.len() > 1 {
+                    e.prompt_hint = format!(" [{}]", names.join(" "));
+                    completion = Some((dir, names, shown));
                 }
             }
-            EditorKey::Char(c) if !c.is_ascii_control() && c < 128 => {
-                // Strictly speaking we don't need to do this, but it's fun!
-                if buf.len() == buf.capacity() - 1 {
-                    buf.reserve(buf.len());
+
+            EditorKey::Char(c) if !c.is_control() => {
+                buf.insert(pos, c);
+                pos += c.len_utf8();
+                // A combining character joins the cluster before it
+                let start = buf[..pos]
+                    .grapheme_indices(true)
+                    .next_back()
+                    .map_or(0, |(i, _)| i);
+                if start < pos - c.len_utf8() {
+                    pos = start + buf[start..].graphemes(true).next().map_or(0, str::len);
                 }
-                buf.push(c as char);
             }
             _ => (),
         }
@@ -998,120 +4277,717 @@ fn editor_prompt(
     }
 }
 
+/// Shows the prompt on the message bar, scrolled so that the cursor is visible.
+fn editor_draw_prompt(e: &mut EditorConfig, prompt: &str, buf: &str, pos: usize) {
+    let line = format!("{}{}{}", prompt, buf, e.prompt_hint);
+    let mut col = str_width(prompt) + str_width(&buf[..pos]);
+    let mut skip = 0;
+    // Keep the last column free for the cursor
+    if col >= e.term_cols {
+        let mut width = 0;
+        for (i, g) in line.grapheme_indices(true) {
+            if col - width < e.term_cols {
+                skip = i;
+                break;
+            }
+            width += grapheme_width(g);
+        }
+        col -= width;
+    }
+    editor_set_status_message(e, &line[skip..]);
+    e.prompt_cursor = Some(col);
+}
+
+/// Replaces a leading `~` with the home directory.
+fn expand_tilde(path: &str) -> String {
+    match std::env::var("HOME") {
+        Ok(home) if path == "~" || path.starts_with("~/") => format!("{}{}", home, &path[1..]),
+        _ => path.to_string(),
+    }
+}
+
+/// Splits `input` into a directory and the start of a file name, and lists the names in the
+/// directory that start with it, directories with a trailing `/`. Hidden files are only
+/// listed if the name starts with a dot.
+fn file_completions(input: &str) -> (String, Vec<String>) {
+    let input = expand_tilde(input);
+    let (dir, start) = input.split_at(input.rfind('/').map_or(0, |i| i + 1));
+    let entries = match std::fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return (dir.to_string(), vec![]),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let mut name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(start) || (name.starts_with('.') && !start.starts_with('.')) {
+                return None;
+            }
+            // Not the entry's file type, so that links to directories count too
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            Some(name)
+        })
+        .collect();
+    names.sort();
+    (dir.to_string(), names)
+}
+
+fn common_prefix(words: &[String]) -> &str {
+    let first = &words[0];
+    let mut end = first.len();
+    for word in &words[1..] {
+        end = first[..end]
+            .char_indices()
+            .zip(word.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(end.min(word.len()), |((i, _), _)| i);
+    }
+    &first[..end]
+}
+
+/// Asks a question that is answered with a single key out of `choices`. Returns `None` on
+/// Escape.
+fn editor_prompt_choice(e: &mut EditorConfig, prompt: &str, choices: &str) -> Option<char> {
+    loop {
+        editor_set_status_message(e, prompt);
+        e.prompt_cursor = Some(str_width(prompt).min(e.term_cols.saturating_sub(1)));
+        editor_refresh_screen(e);
+        match editor_read_key(e) {
+            EditorKey::Escape => {
+                editor_set_status_message(e, "");
+                e.prompt_cursor = None;
+                return None;
+            }
+            EditorKey::Char(c) if choices.contains(c) => {
+                editor_set_status_message(e, "");
+                e.prompt_cursor = None;
+                return Some(c);
+            }
+            _ => (),
+        }
+    }
+}
+
 fn editor_move_cursor(key: &EditorKey, e: &mut EditorConfig) {
-    let row_old = e.rows.get(e.cy).map(|r| &r.chars);
-    let rowlen_old = row_old.map(String::len).unwrap_or(0);
+    let row_old = e.buf.rows.get(e.cy);
+    let rowlen_old = row_old.map(ERow::len).unwrap_or(0);
     match key {
-        EditorKey::ArrowLeft if e.cx > 0 => e.cx -= 1,
+        EditorKey::ArrowLeft if e.cx > 0 => e.cx = e.buf.rows[e.cy].prev_boundary(e.cx),
         EditorKey::ArrowLeft if e.cy > 0 => {
             e.cy -= 1;
-            e.cx = e.rows[e.cy].chars.len();
+            e.cx = e.buf.rows[e.cy].len();
         }
-        EditorKey::ArrowRight if e.cx < rowlen_old => e.cx += 1,
+        EditorKey::ArrowRight if e.cx < rowlen_old => e.cx = e.buf.rows[e.cy].next_boundary(e.cx),
         EditorKey::ArrowRight if row_old.is_some() && rowlen_old == e.cx => {
             e.cy += 1;
             e.cx = 0;
         }
         EditorKey::ArrowUp if e.cy > 0 => e.cy -= 1,
-        EditorKey::ArrowDown if e.cy < e.rows.len() - 1 => e.cy += 1,
+        EditorKey::ArrowDown if e.cy + 1 < e.buf.rows.len() => e.cy += 1,
         _ => (),
     }
 
-    let row_new = e.rows.get(e.cy).map(|r| &r.chars);
-    let rowlen_new = row_new.map(String::len).unwrap_or(0);
-    if e.cx > rowlen_new {
-        e.cx = rowlen_new;
+    e.cx = e
+        .buf
+        .rows
+        .get(e.cy)
+        .map(|r| r.snap_to_boundary(e.cx))
+        .unwrap_or(0);
+}
+
+/// Asks again before quitting with unsaved changes, `confirm_quit_times` times.
+fn editor_quit(e: &mut EditorConfig) {
+    let dirty_buffers = (0..e.buffers.len())
+        .filter(|&i| editor_buffer(e, i).dirty)
+        .count();
+    if dirty_buffers > 0 && e.quit_times > 0 {
+        let what = if dirty_buffers == 1 && e.buffers.len() == 1 {
+            "File has".to_string()
+        } else {
+            format!("{} buffers have", dirty_buffers)
+        };
+        let keys = editor_command_keys(e, "quit").unwrap_or_else(|| "quit".to_string());
+        editor_set_status_message(
+            e,
+            &format!(
+                "WARNING!!! {} unsaved changes. Press {} {} more times to quit.",
+                what, keys, e.quit_times
+            ),
+        );
+        e.quit_times -= 1;
+        e.last_command = LastCommand::Quit;
+        return;
     }
+    editor_swap_remove(&mut e.buf);
+    for b in e.buffers.iter_mut() {
+        editor_swap_remove(b);
+    }
+    print!("\x1b[2J\x1b[H");
+    flush_stdout();
+    exit(0);
 }
 
-fn editor_process_keypress(e: &mut EditorConfig) {
-    let key = editor_read_key();
-    match key {
-        EditorKey::Return => editor_insert_new_line(e),
-
-        EditorKey::Char(c) if c == ctrl_key(b'q') => {
-            if e.dirty && e.quit_times > 0 {
-                editor_set_status_message(
-                    e,
-                    &format!(
-                        "WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
-                        e.quit_times
-                    ),
-                );
-                e.quit_times -= 1;
-                return;
-            }
-            print!("\x1b[2J\x1b[H");
-            flush_stdout();
-            exit(0);
-        }
+fn editor_page(e: &mut EditorConfig, up: bool) {
+    let arrow = if up {
+        e.cy = e.rowoff;
+        EditorKey::ArrowUp
+    } else {
+        e.cy = e.buf.rows.len().min(e.rowoff + e.screenrows - 1);
+        EditorKey::ArrowDown
+    };
+    for _ in 0..e.screenrows {
+        editor_move_cursor(&arrow, e);
+    }
+}
 
-        EditorKey::Char(c) if c == ctrl_key(b's') => editor_save(e),
+fn editor_toggle_search_highlight(e: &mut EditorConfig) {
+    e.find.highlight = !e.find.highlight;
+    let msg = match (&e.find.last_search, e.find.highlight) {
+        (None, true) => "Highlighting searches (no search yet)",
+        (_, true) => "Highlighting the last search",
+        (_, false) => "Search highlighting off",
+    };
+    editor_set_status_message(e, msg);
+}
 
-        EditorKey::ArrowDown
-        | EditorKey::ArrowUp
-        | EditorKey::ArrowLeft
-        | EditorKey::ArrowRight => editor_move_cursor(&key, e),
+fn editor_process_keypress(e: &mut EditorConfig) {
+    e.awaiting_command = true;
+    let mut keys = vec![editor_read_key(e)];
+    e.awaiting_command = false;
+    // Keys that start a longer binding wait for the rest of it
+    let command = loop {
+        if let Some(&command) = e.keymap.get(&keys) {
+            break Some(command);
+        }
+        let is_prefix = e
+            .keymap
+            .range(keys.clone()..)
+            .next()
+            .is_some_and(|(bound, _)| bound.starts_with(&keys));
+        if !is_prefix {
+            break None;
+        }
+        editor_set_status_message(e, &format!("{}-", keys_name(&keys)));
+        editor_refresh_screen(e);
+        keys.push(editor_read_key(e));
+    };
+    if keys.len() > 1 {
+        editor_set_status_message(e, "");
+    }
 
-        EditorKey::Home => e.cx = 0,
-        EditorKey::End => {
-            if e.cy < e.rows.len() {
-                e.cx = e.rows[e.cy].chars.len();
-            }
+    let cursor_before = (e.cx, e.cy);
+    let mut typing = false;
+    let last_command = std::mem::replace(&mut e.last_command, LastCommand::Other);
+    match (command, keys.as_slice()) {
+        (Some(command), _) => (command.run)(e, &last_command),
+        // Characters without a binding insert themselves
+        (None, &[EditorKey::Char(c)]) => {
+            editor_insert_char(e, c);
+            typing = true;
         }
+        (None, &[_]) => (),
+        (None, _) => {
+            editor_set_status_message(e, &format!("{} is undefined", keys_name(&keys)));
+        }
+    }
 
-        EditorKey::Char(c) if c == ctrl_key(b'f') => editor_find(e),
+    // Editing the text ends the selection, like in Emacs' transient mark mode
+    if !e.buf.undo.pending.is_empty() {
+        e.buf.mark = None;
+    }
+    editor_undo_commit(e, cursor_before, typing);
+    if !matches!(e.last_command, LastCommand::Quit) {
+        e.quit_times = e.confirm_quit_times;
+    }
+}
 
-        ref k if is_backspace_or_delete(k) => {
-            if *k == EditorKey::Delete {
-                editor_move_cursor(&EditorKey::ArrowRight, e);
+/*** keymap ***/
+
+static COMMANDS: &[EditorCommand] = &[
+    EditorCommand {
+        name: "quit",
+        run: |e, _| editor_quit(e),
+    },
+    EditorCommand {
+        name: "save",
+        run: |e, _| editor_save(e),
+    },
+    EditorCommand {
+        name: "open-file",
+        run: |e, _| {
+            if let Some(filename) =
+                editor_prompt(e, "Open file (ESC to cancel): ", PromptKind::File, None)
+            {
+                editor_open_buffer(e, &filename);
             }
+        },
+    },
+    EditorCommand {
+        name: "reload-file",
+        run: |e, _| editor_reload(e),
+    },
+    EditorCommand {
+        name: "reload-config",
+        run: |e, _| editor_config_reload(e),
+    },
+    EditorCommand {
+        name: "toggle-line-ending",
+        run: |e, _| editor_toggle_line_ending(e),
+    },
+    EditorCommand {
+        name: "newline",
+        run: |e, _| editor_insert_new_line(e),
+    },
+    EditorCommand {
+        name: "delete-backward-char",
+        run: |e, _| editor_del_char(e),
+    },
+    EditorCommand {
+        name: "delete-char",
+        run: |e, _| {
+            editor_move_cursor(&EditorKey::ArrowRight, e);
             editor_del_char(e);
-        }
+        },
+    },
+    EditorCommand {
+        name: "undo",
+        run: |e, _| editor_undo(e),
+    },
+    EditorCommand {
+        name: "redo",
+        run: |e, _| editor_redo(e),
+    },
+    EditorCommand {
+        name: "set-mark",
+        run: |e, _| editor_toggle_mark(e),
+    },
+    EditorCommand {
+        name: "clear-mark",
+        run: |e, _| e.buf.mark = None,
+    },
+    EditorCommand {
+        name: "cut",
+        run: |e, last_command| editor_copy(e, true, last_command),
+    },
+    EditorCommand {
+        name: "copy",
+        run: |e, last_command| editor_copy(e, false, last_command),
+    },
+    EditorCommand {
+        name: "kill-line",
+        run: |e, last_command| editor_kill_line(e, last_command),
+    },
+    EditorCommand {
+        name: "yank",
+        run: |e, _| editor_yank(e, false),
+    },
+    EditorCommand {
+        name: "yank-clipboard",
+        run: |e, _| editor_yank(e, true),
+    },
+    EditorCommand {
+        name: "yank-pop",
+        run: |e, last_command| editor_yank_pop(e, last_command),
+    },
+    EditorCommand {
+        name: "next-buffer",
+        run: |e, _| editor_cycle_buffer(e, 1),
+    },
+    EditorCommand {
+        name: "previous-buffer",
+        run: |e, _| editor_cycle_buffer(e, -1),
+    },
+    EditorCommand {
+        name: "list-buffers",
+        run: |e, _| editor_list_buffers(e),
+    },
+    EditorCommand {
+        name: "close-buffer",
+        run: |e, _| editor_close_buffer(e),
+    },
+    EditorCommand {
+        name: "split-window-below",
+        run: |e, _| editor_split_window(e, false),
+    },
+    EditorCommand {
+        name: "split-window-right",
+        run: |e, _| editor_split_window(e, true),
+    },
+    EditorCommand {
+        name: "other-window",
+        run: |e, _| editor_cycle_window(e, 1),
+    },
+    EditorCommand {
+        name: "close-window",
+        run: |e, _| editor_close_window(e),
+    },
+    EditorCommand {
+        name: "enlarge-window",
+        run: |e, _| editor_resize_window(e, 1),
+    },
+    EditorCommand {
+        name: "shrink-window",
+        run: |e, _| editor_resize_window(e, -1),
+    },
+    EditorCommand {
+        name: "previous-line",
+        run: |e, _| editor_move_cursor(&EditorKey::ArrowUp, e),
+    },
+    EditorCommand {
+        name: "next-line",
+        run: |e, _| editor_move_cursor(&EditorKey::ArrowDown, e),
+    },
+    EditorCommand {
+        name: "backward-char",
+        run: |e, _| editor_move_cursor(&EditorKey::ArrowLeft, e),
+    },
+    EditorCommand {
+        name: "forward-char",
+        run: |e, _| editor_move_cursor(&EditorKey::ArrowRight, e),
+    },
+    EditorCommand {
+        name: "beginning-of-line",
+        run: |e, _| e.cx = 0,
+    },
+    EditorCommand {
+        name: "end-of-line",
+        run: |e, _| {
+            if e.cy < e.buf.rows.len() {
+                e.cx = e.buf.rows[e.cy].len();
+            }
+        },
+    },
+    EditorCommand {
+        name: "page-up",
+        run: |e, _| editor_page(e, true),
+    },
+    EditorCommand {
+        name: "page-down",
+        run: |e, _| editor_page(e, false),
+    },
+    EditorCommand {
+        name: "find",
+        run: |e, _| editor_find(e),
+    },
+    EditorCommand {
+        name: "replace",
+        run: |e, _| editor_replace(e),
+    },
+    EditorCommand {
+        name: "toggle-search-highlight",
+        run: |e, _| editor_toggle_search_highlight(e),
+    },
+    // The screen is redrawn after every key anyway
+    EditorCommand {
+        name: "redraw",
+        run: |_, _| (),
+    },
+];
+
+static DEFAULT_KEYMAP: &[(&str, &str)] = &[
+    ("C-q", "quit"),
+    ("C-s", "save"),
+    ("C-o", "open-file"),
+    ("M-r", "reload-file"),
+    ("M-R", "reload-config"),
+    ("M-l", "toggle-line-ending"),
+    ("Return", "newline"),
+    ("Backspace", "delete-backward-char"),
+    ("C-h", "delete-backward-char"),
+    ("Delete", "delete-char"),
+    ("C-z", "undo"),
+    ("C-r", "redo"),
+    ("C-@", "set-mark"),
+    ("C-g", "clear-mark"),
+    ("C-w", "cut"),
+    ("M-w", "copy"),
+    ("C-k", "kill-line"),
+    ("C-y", "yank"),
+    ("M-y", "yank-pop"),
+    ("M-Y", "yank-clipboard"),
+    ("M-n", "next-buffer"),
+    ("M-p", "previous-buffer"),
+    ("M-b", "list-buffers"),
+    ("M-k", "close-buffer"),
+    ("M-s", "split-window-below"),
+    ("M-v", "split-window-right"),
+    ("M-o", "other-window"),
+    ("M-c", "close-window"),
+    ("M-+", "enlarge-window"),
+    ("M--", "shrink-window"),
+    ("Up", "previous-line"),
+    ("Down", "next-line"),
+    ("Left", "backward-char"),
+    ("Right", "forward-char"),
+    ("Home", "beginning-of-line"),
+    ("End", "end-of-line"),
+    ("PageUp", "page-up"),
+    ("PageDown", "page-down"),
+    ("C-f", "find"),
+    ("M-%", "replace"),
+    ("M-h", "toggle-search-highlight"),
+    ("C-l", "redraw"),
+];
+
+fn default_keymap() -> BTreeMap<Vec<EditorKey>, &'static EditorCommand> {
+    DEFAULT_KEYMAP
+        .iter()
+        .map(|&(keys, name)| {
+            (
+                parse_keys(keys).unwrap(),
+                COMMANDS.iter().find(|c| c.name == name).unwrap(),
+            )
+        })
+        .collect()
+}
 
-        EditorKey::PageDown | EditorKey::PageUp => {
-            let arrow = if key == EditorKey::PageUp {
-                e.cy = e.rowoff;
-                EditorKey::ArrowUp
+/// Parses a key written like Emacs does: `a`, `C-a` for Ctrl, `M-a` for Alt, or the name of
+/// a special key like `PageUp`.
+fn parse_key(name: &str) -> Option<EditorKey> {
+    Some(match name {
+        "Up" => EditorKey::ArrowUp,
+        "Down" => EditorKey::ArrowDown,
+        "Left" => EditorKey::ArrowLeft,
+        "Right" => EditorKey::ArrowRight,
+        "Home" => EditorKey::Home,
+        "End" => EditorKey::End,
+        "PageUp" => EditorKey::PageUp,
+        "PageDown" => EditorKey::PageDown,
+        "Delete" => EditorKey::Delete,
+        "Return" => EditorKey::Return,
+        "Escape" => EditorKey::Escape,
+        "Backspace" => EditorKey::Char(BACKSPACE),
+        "Tab" => EditorKey::Char('\t'),
+        "Space" => EditorKey::Char(' '),
+        _ => {
+            if let Some(rest) = name.strip_prefix("M-") {
+                match parse_key(rest)? {
+                    EditorKey::Char(c) => EditorKey::Alt(c),
+                    _ => return None,
+                }
+            } else if let Some(rest) = name.strip_prefix("C-") {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if ('@'..='~').contains(&c) => EditorKey::Char(ctrl_key(c)),
+                    _ => return None,
+                }
             } else {
-                e.cy = e.rows.len().min(e.rowoff + e.screenrows - 1);
-                EditorKey::ArrowDown
-            };
-            for _ in 0..e.screenrows {
-                editor_move_cursor(&arrow, e);
+                let mut chars = name.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if !c.is_control() => EditorKey::Char(c),
+                    _ => return None,
+                }
             }
         }
+    })
+}
 
-        EditorKey::Char(c) if c == ctrl_key(b'l') || c == b'\x1b' => (),
+/// Parses space separated keys, like `C-x C-s`.
+fn parse_keys(names: &str) -> Option<Vec<EditorKey>> {
+    let keys = names
+        .split_whitespace()
+        .map(parse_key)
+        .collect::<Option<Vec<_>>>()?;
+    Some(keys).filter(|keys| !keys.is_empty())
+}
 
-        EditorKey::Char(c) => editor_insert_char(e, c.into()),
-        _ => (),
+fn key_name(key: &EditorKey) -> String {
+    match *key {
+        EditorKey::ArrowUp => "Up".into(),
+        EditorKey::ArrowDown => "Down".into(),
+        EditorKey::ArrowLeft => "Left".into(),
+        EditorKey::ArrowRight => "Right".into(),
+        EditorKey::Home => "Home".into(),
+        EditorKey::End => "End".into(),
+        EditorKey::PageUp => "PageUp".into(),
+        EditorKey::PageDown => "PageDown".into(),
+        EditorKey::Delete => "Delete".into(),
+        EditorKey::Return => "Return".into(),
+        EditorKey::Escape => "Escape".into(),
+        EditorKey::Char(BACKSPACE) => "Backspace".into(),
+        EditorKey::Char('\t') => "Tab".into(),
+        EditorKey::Char(' ') => "Space".into(),
+        EditorKey::Char(c) if c < ' ' => {
+            // Letters are lowercase, C-@ and C-[ to C-_ aren't
+            let base = if ('\x01'..='\x1a').contains(&c) {
+                0x60
+            } else {
+                0x40
+            };
+            format!("C-{}", (c as u8 | base) as char)
+        }
+        EditorKey::Char(c) => c.to_string(),
+        EditorKey::Alt(c) => format!("M-{}", key_name(&EditorKey::Char(c))),
     }
+}
 
-    e.quit_times = BYOTE_QUIT_TIMES;
+fn keys_name(keys: &[EditorKey]) -> String {
+    keys.iter().map(key_name).collect::<Vec<_>>().join(" ")
+}
+
+/// The first keys bound to the command called `name`.
+fn editor_command_keys(e: &EditorConfig, name: &str) -> Option<String> {
+    e.keymap
+        .iter()
+        .find(|(_, command)| command.name == name)
+        .map(|(keys, _)| keys_name(keys))
+}
+
+/// Lists the keys of the most important commands, as they're currently bound.
+fn editor_help_message(e: &EditorConfig) -> String {
+    let help: Vec<String> = [
+        ("save", "save"),
+        ("quit", "quit"),
+        ("find", "find"),
+        ("undo", "undo"),
+        ("redo", "redo"),
+    ]
+    .iter()
+    .filter_map(|&(name, what)| Some(format!("{} = {}", editor_command_keys(e, name)?, what)))
+    .collect();
+    format!("HELP: {}", help.join(" | "))
 }
 
 /*** init ***/
 
 fn init_editor() -> EditorConfig {
-    unwrap_or_die("init_editor", EditorConfig::from_env())
+    let mut e = unwrap_or_die("init_editor", EditorConfig::from_env());
+    let mut errors = editor_config_load(&mut e);
+    errors.extend(editor_syntax_load(&mut e));
+    editor_report_config_errors(&mut e, &errors);
+    e
 }
 
 fn main() {
     enable_raw_mode();
     let mut e = init_editor();
+    editor_history_load(&mut e);
 
-    editor_set_status_message(
-        &mut e,
-        "HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find",
-    );
+    // Problems with the config file are more important
+    if e.statusmsg.is_empty() {
+        let help = editor_help_message(&e);
+        editor_set_status_message(&mut e, &help);
+    }
 
-    if let Some(filename) = std::env::args().nth(1) {
-        editor_open(&mut e, &filename)
+    let filenames: Vec<String> = std::env::args().skip(1).collect();
+    for filename in &filenames {
+        editor_open_buffer(&mut e, filename);
     }
+    editor_switch_buffer(&mut e, 0);
 
     loop {
         editor_refresh_screen(&mut e);
         editor_process_keypress(&mut e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor_with(text: &str) -> EditorConfig {
+        let mut e = EditorConfig::new(24, 80);
+        editor_load_bytes(&mut e, text.as_bytes());
+        e
+    }
+
+    fn row_numbers(e: &EditorConfig) -> Vec<usize> {
+        e.buf.rows.iter().map(|row| row.idx).collect()
+    }
+
+    #[test]
+    fn undo_new_line_at_start_of_file() {
+        let mut e = editor_with("a\nb");
+        editor_insert_new_line(&mut e);
+        editor_undo_commit(&mut e, (0, 0), false);
+        assert_eq!(row_numbers(&e), [0, 1, 2]);
+
+        editor_undo(&mut e);
+        assert_eq!(row_numbers(&e), [0, 1]);
+        assert_eq!(editor_rows_to_bytes(&e), b"a\nb");
+    }
+
+    #[test]
+    fn yank_pop_after_last_row() {
+        let mut e = editor_with("aaa\nbbb\n");
+        e.kill_ring = vec!["X".into(), "Y".into()];
+        e.cy = 2;
+        editor_yank(&mut e, false);
+        let yank = std::mem::replace(&mut e.last_command, LastCommand::Other);
+        editor_yank_pop(&mut e, &yank);
+        assert_eq!(editor_rows_to_bytes(&e), b"aaa\nbbb\nX\n");
+    }
+
+    #[test]
+    fn key_after_queued_escape_sequence() {
+        let mut e = editor_with("");
+        e.pending_input.extend(b"\x1b[Ax\x1b[3~");
+        assert!(editor_read_key(&mut e) == EditorKey::ArrowUp);
+        assert!(editor_read_key(&mut e) == EditorKey::Char('x'));
+        assert!(editor_read_key(&mut e) == EditorKey::Delete);
+    }
+
+    #[test]
+    fn diff_inserted_line() {
+        let lines = |s: &str| -> Vec<String> { s.split(' ').map(String::from).collect() };
+        let old = lines("a b c d");
+        let new = lines("a x b c y");
+        let hunks: Vec<_> = diff_lines(&old, &new)
+            .into_iter()
+            .map(|(at, removed, added)| (at, removed.to_vec(), added.to_vec()))
+            .collect();
+        assert_eq!(
+            hunks,
+            [(1, vec![], lines("x")), (3, lines("d"), lines("y")),]
+        );
+    }
+
+    #[test]
+    fn resize_stays_within_split() {
+        let mut layout = Layout::Split {
+            vertical: true,
+            first_size: 40,
+            first: Box::new(Layout::Window(0)),
+            second: Box::new(Layout::Window(1)),
+        };
+        let first_size = |layout: &Layout| match layout {
+            Layout::Split { first_size, .. } => *first_size,
+            Layout::Window(_) => unreachable!(),
+        };
+        assert!(layout_resize(&mut layout, (23, 80), 0, 100));
+        assert_eq!(first_size(&layout), 78);
+        assert!(layout_resize(&mut layout, (23, 80), 0, -1));
+        assert_eq!(first_size(&layout), 77);
+        assert!(layout_resize(&mut layout, (23, 80), 0, -100));
+        assert_eq!(first_size(&layout), 1);
+    }
+
+    #[test]
+    fn find_in_no_rows() {
+        let e = editor_with("");
+        let regex = Regex::new("x*").unwrap();
+        assert!(find_in_rows(&e.buf.rows, 0, 0, &regex, false).is_empty());
+    }
+
+    #[test]
+    fn backslash_ends_unescaped_strings() {
+        for (filename, line) in [
+            ("a.rs", "br\"\\\" //"),
+            ("a.sh", "echo '\\' #"),
+            ("a.toml", "a = '\\' #"),
+        ] {
+            let mut e = editor_with(line);
+            e.syntaxes = BUILTIN_SYNTAXES
+                .iter()
+                .map(|data| Rc::new(parse_syntax(data, Path::new("built-in syntax")).unwrap()))
+                .collect();
+            e.buf.filename = Some(filename.into());
+            editor_select_syntax_highlight(&mut e);
+            let row = &e.buf.rows[0];
+            assert!(row.hl_open_string.is_none(), "{}", filename);
+            assert!(row.hl.last() == Some(&Highlight::Comment), "{}", filename);
+        }
+    }
+}